@@ -0,0 +1,22 @@
+fn main() {
+    let git_commit = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or_else(|| String::from("unknown"), |commit| commit.trim().into());
+
+    println!("cargo:rustc-env=FILE_SHARER_GIT_COMMIT={git_commit}");
+    println!(
+        "cargo:rustc-env=FILE_SHARER_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=FILE_SHARER_PROFILE={}",
+        std::env::var("PROFILE").unwrap_or_default()
+    );
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
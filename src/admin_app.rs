@@ -1,33 +1,198 @@
 use std::{
     future::Future,
     net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
 };
 
 use askama_axum::IntoResponse as _;
 use axum::{
     extract::{Form, Multipart},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Json, Router,
 };
 use axum_extra::routing::{RouterExt, TypedPath};
 
 use crate::{
-    controller::{Admin, ByteCount, ShareConfig, ShareListing, Token, UploadConfig, UploadListing},
-    timestamp::WebTimestamp,
+    content::{self, ListingFormat},
+    controller::{
+        Admin, ByteCount, ExchangeConfig, FileEntry, FileExists, Filename, NewExchange, OpenedFile,
+        QuotaExceeded, ShareConfig, SharedReference, StorageUsage, Token, TokenDetails,
+        TokenFilter, TokenListing, TokenStatus, UploadConfig, UploadRestrictions,
+    },
+    health, logging, metrics, qr,
+    timestamp::{Timestamp, WebDate, WebTimestamp},
+    webhooks::Webhook,
 };
 
 #[derive(askama::Template)]
 #[template(path = "admin.html")]
 struct HomePage {
-    shares: Vec<ShareListing>,
+    search: Search,
+    now: Timestamp,
+    shares: Vec<TokenListing>,
     new_share: NewShare,
-    uploads: Vec<UploadListing>,
+    uploads: Vec<TokenListing>,
     new_upload: NewUpload,
+    exchanges: Vec<TokenListing>,
+    new_exchange: NewExchangeForm,
+    storage: StorageUsage,
+}
+
+impl HomePage {
+    fn is_expired(&self, listing: &TokenListing) -> bool {
+        listing.status(self.now) == TokenStatus::Expired
+    }
+
+    /// How long until, or since, a time, e.g. "in 3 days" or "2 hours ago"
+    fn relative_time(&self, timestamp: &Timestamp) -> String {
+        let duration = *timestamp - self.now;
+
+        let (amount, unit) = match duration.whole_minutes().abs() {
+            minutes if minutes >= 24 * 60 => (minutes / (24 * 60), "day"),
+            minutes if minutes >= 60 => (minutes / 60, "hour"),
+            0 => return "just now".to_owned(),
+            minutes => (minutes, "minute"),
+        };
+
+        let plural = if amount == 1 { "" } else { "s" };
+
+        if duration.is_negative() {
+            format!("{amount} {unit}{plural} ago")
+        } else {
+            format!("in {amount} {unit}{plural}")
+        }
+    }
+
+    fn total_files(&self, listings: &[TokenListing]) -> u64 {
+        listings
+            .iter()
+            .filter_map(|listing| listing.usage)
+            .map(|usage| usage.file_count)
+            .sum()
+    }
+
+    fn total_stored_size(&self, listings: &[TokenListing]) -> ByteCount {
+        let mut total = ByteCount(0);
+
+        for usage in listings.iter().filter_map(|listing| listing.usage) {
+            total += usage.stored_size;
+        }
+
+        total
+    }
+
+    fn total_downloads(&self, listings: &[TokenListing]) -> u64 {
+        listings
+            .iter()
+            .map(|listing| listing.activity.downloads)
+            .sum()
+    }
+
+    fn tag_query(&self, tag: &str) -> String {
+        format!("?tag={}", content::encode_path_segment(tag))
+    }
+}
+
+/// Which tokens the home page lists, as entered into its search form
+#[derive(Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Search {
+    #[serde(default)]
+    search: String,
+    #[serde(default)]
+    tag: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    status: Option<TokenStatus>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    created_from: Option<WebDate>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    created_to: Option<WebDate>,
+    /// Overrides the format requested with the `Accept` header
+    format: Option<ListingFormat>,
+}
+
+impl Search {
+    fn filter(&self) -> TokenFilter {
+        let search = self.search.trim();
+        let tag = self.tag.trim().to_lowercase();
+
+        TokenFilter {
+            search: (!search.is_empty()).then(|| search.into()),
+            tag: (!tag.is_empty()).then_some(tag),
+            status: self.status,
+            created_from: self.created_from,
+            created_to: self.created_to,
+        }
+    }
+
+    fn is_status(&self, status: &str) -> bool {
+        self.status == status.parse().ok()
+    }
+}
+
+/// The tokens listed by the home page, for scripts which manage them
+#[derive(serde::Serialize)]
+struct JsonTokens {
+    shares: Vec<JsonToken>,
+    uploads: Vec<JsonToken>,
+    exchanges: Vec<JsonToken>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonToken {
+    token: Token,
+    name: String,
+    url: String,
+    status: TokenStatus,
+    expiry: Option<String>,
+    created: Option<String>,
+    notes: String,
+    tags: Vec<String>,
+    file_count: Option<u64>,
+    stored_size: Option<ByteCount>,
+    quota_remaining: Option<ByteCount>,
+    downloads: u64,
+    last_accessed: Option<String>,
+}
+
+impl JsonToken {
+    fn list(
+        listings: Vec<TokenListing>,
+        category: &str,
+        now: Timestamp,
+        admin: &Admin,
+    ) -> Vec<Self> {
+        listings
+            .into_iter()
+            .map(|listing| Self {
+                url: admin.config().token_url(category, &listing.token),
+                status: listing.status(now),
+                expiry: listing.expiry.to_rfc3339().ok(),
+                created: listing
+                    .created
+                    .and_then(|created| created.to_rfc3339().ok()),
+                file_count: listing.usage.map(|usage| usage.file_count),
+                stored_size: listing.usage.map(|usage| usage.stored_size),
+                quota_remaining: listing.quota_remaining,
+                downloads: listing.activity.downloads,
+                last_accessed: listing
+                    .activity
+                    .last_accessed
+                    .and_then(|last_accessed| last_accessed.to_rfc3339().ok()),
+                token: listing.token,
+                name: listing.name,
+                notes: listing.details.notes,
+                tags: listing.details.tags,
+            })
+            .collect()
+    }
 }
 
 async fn home_page(
+    axum::extract::Query(search): axum::extract::Query<Search>,
+    headers: axum::http::HeaderMap,
     admin: axum::extract::Extension<Admin>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let shares = admin.current_shares().await.map_err(|err| {
@@ -36,36 +201,109 @@ async fn home_page(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    let now = WebTimestamp::now().map_err(|err| {
-        tracing::error!("Failed to get current time: {err}",);
+    let uploads = admin.current_uploads().await.map_err(|err| {
+        tracing::error!("Failed to get current uploads: {err:#}");
 
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    let new_share = NewShare {
-        name: String::new(),
-        expiry: now + time::Duration::days(1),
-    };
+    let exchanges = admin.current_exchanges().await.map_err(|err| {
+        tracing::error!("Failed to get current exchanges: {err:#}");
 
-    let uploads = admin.current_uploads().await.map_err(|err| {
-        tracing::error!("Failed to get current uploads: {err:#}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let now = Timestamp::now().map_err(|err| {
+        tracing::error!("Failed to get current time: {err}",);
 
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    // Totals are of all tokens, not just those which are searched for
+    let storage = admin
+        .listed_storage_usage(&shares, &uploads)
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to get storage usage: {err:#}");
+
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let filter = search.filter();
+
+    let [shares, uploads, exchanges] = [shares, uploads, exchanges].map(|listings| {
+        listings
+            .into_iter()
+            .filter(|listing| filter.matches(listing, now))
+            .collect::<Vec<_>>()
+    });
+
+    let format = search.format.unwrap_or(if content::accepts_json(&headers) {
+        ListingFormat::Json
+    } else {
+        ListingFormat::Html
+    });
+
+    let vary = [(axum::http::header::VARY, "accept")];
+
+    if format == ListingFormat::Json {
+        return Ok((
+            vary,
+            Json(JsonTokens {
+                shares: JsonToken::list(shares, "share", now, &admin),
+                uploads: JsonToken::list(uploads, "upload", now, &admin),
+                exchanges: JsonToken::list(exchanges, "exchange", now, &admin),
+            }),
+        )
+            .into_response());
+    }
+
+    let new_token_expiry = WebTimestamp::from(now);
+
+    let new_share = NewShare {
+        name: String::new(),
+        expiry: new_token_expiry + time::Duration::days(1),
+        download_rate_limit: None,
+        end_to_end: false,
+        common: NewTokenFields::default(),
+    };
+
     let new_upload = NewUpload {
         name: String::new(),
-        expiry: now + time::Duration::days(1),
+        expiry: new_token_expiry + time::Duration::days(1),
         space_quota: ByteCount(1_000_000_000),
+        allowed_types: String::new(),
+        max_file_size: None,
+        max_file_count: None,
+        common: NewTokenFields::default(),
     };
 
-    Ok(HomePage {
-        shares,
-        new_share,
-        uploads,
-        new_upload,
-    }
-    .into_response())
+    let new_exchange = NewExchangeForm {
+        name: String::new(),
+        expiry: new_token_expiry + time::Duration::days(7),
+        space_quota: ByteCount(1_000_000_000),
+        allowed_types: String::new(),
+        max_file_size: None,
+        max_file_count: None,
+        common: NewTokenFields::default(),
+    };
+
+    Ok((
+        vary,
+        HomePage {
+            search,
+            now,
+            shares,
+            new_share,
+            uploads,
+            new_upload,
+            exchanges,
+            new_exchange,
+            storage,
+        }
+        .into_response(),
+    )
+        .into_response())
 }
 
 #[derive(TypedPath, serde::Deserialize)]
@@ -77,27 +315,141 @@ struct SharePagePath {
 #[derive(askama::Template)]
 #[template(path = "admin_share.html")]
 struct SharePage {
+    token: Token,
     name: String,
     expiry: WebTimestamp,
+    details: TokenDetails,
+    download_rate_limit: Option<ByteCount>,
+    encrypted: bool,
+    end_to_end: bool,
+    webhooks: Vec<Webhook>,
+    email_recipients: Vec<String>,
     upload_url: String,
+    /// `None` for end-to-end encrypted shares, as the server doesn't know the full URL
+    qr_code: Option<String>,
+    files: Vec<FileEntry>,
+    /// The only file which the link leads to, if any
+    single_file: Option<String>,
+    references: Vec<SharedReference>,
+    /// Where files may be shared by reference from. Empty if sharing by reference is disabled
+    share_roots: Vec<PathBuf>,
+}
+
+fn qr_code(url: &str) -> Result<String, StatusCode> {
+    qr::svg(url).map_err(|err| {
+        tracing::error!("{err:#}");
+
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
 }
 
 async fn current_share(
     SharePagePath { token }: SharePagePath,
     admin: axum::Extension<Admin>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let ShareConfig { name, expiry } = admin.current_share_config(&token).await.map_err(|err| {
+    let ShareConfig {
+        name,
+        expiry,
+        download_rate_limit,
+        wrapped_key,
+        end_to_end,
+        webhooks,
+        email_recipients,
+        single_file,
+        details,
+    } = admin.current_share_config(&token).await.map_err(|err| {
         tracing::error!("{err:#}");
 
         StatusCode::NOT_FOUND
     })?;
 
+    let files = admin.shared_files(&token).await.map_err(|err| {
+        tracing::error!("Failed to list shared files: {err:#}");
+
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let references = admin.shared_references(&token).await.map_err(|err| {
+        tracing::error!("Failed to list shared references: {err:#}");
+
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // For end-to-end encrypted shares, the key is appended by the browser
     let upload_url = admin.config().token_url("share", &token);
 
+    let qr_code = if end_to_end {
+        None
+    } else {
+        Some(qr_code(&upload_url)?)
+    };
+
     Ok(SharePage {
+        token,
         name,
         expiry: expiry.into(),
+        details,
+        download_rate_limit,
+        encrypted: wrapped_key.is_some(),
+        end_to_end,
+        webhooks,
+        email_recipients,
         upload_url,
+        qr_code,
+        files,
+        single_file,
+        references,
+        share_roots: admin.config().share_roots.clone(),
+    }
+    .into_response())
+}
+
+#[derive(askama::Template)]
+#[template(path = "admin_handout.html")]
+struct Handout {
+    kind: &'static str,
+    name: String,
+    expiry: WebTimestamp,
+    url: String,
+    qr_code: String,
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/handout/share/:token")]
+struct ShareHandoutPath {
+    token: Token,
+}
+
+/// A printable page with the share's link and QR code
+async fn share_handout(
+    ShareHandoutPath { token }: ShareHandoutPath,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let ShareConfig {
+        name,
+        expiry,
+        end_to_end,
+        ..
+    } = admin.current_share_config(&token).await.map_err(|err| {
+        tracing::error!("{err:#}");
+
+        StatusCode::NOT_FOUND
+    })?;
+
+    if end_to_end {
+        tracing::error!("End-to-end encrypted shares have no handout, as the key is unknown");
+
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let url = admin.config().token_url("share", &token);
+
+    Ok(Handout {
+        kind: "Share",
+        name,
+        expiry: expiry.into(),
+        qr_code: qr_code(&url)?,
+        url,
     }
     .into_response())
 }
@@ -107,16 +459,37 @@ async fn current_share(
 struct NewShare {
     name: String,
     expiry: WebTimestamp,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    download_rate_limit: Option<u64>,
+    #[serde(default)]
+    end_to_end: bool,
+    #[serde(flatten)]
+    common: NewTokenFields,
 }
 
 async fn new_share(
-    Form(NewShare { name, expiry }): Form<NewShare>,
+    Form(NewShare {
+        name,
+        expiry,
+        download_rate_limit,
+        end_to_end,
+        common,
+    }): Form<NewShare>,
     admin: axum::Extension<Admin>,
 ) -> Result<impl IntoResponse, StatusCode> {
+    let (details, webhooks, email_recipients) = common.parse();
+
     let new_token = admin
         .new_share_token(ShareConfig {
             name,
             expiry: expiry.into(),
+            download_rate_limit: download_rate_limit.map(ByteCount),
+            wrapped_key: None,
+            end_to_end,
+            details,
+            webhooks,
+            email_recipients,
+            single_file: None,
         })
         .await
         .map_err(|err| {
@@ -137,17 +510,142 @@ async fn share_files(
     ShareTokenPath { token }: ShareTokenPath,
     files: Multipart,
     admin: axum::Extension<Admin>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, Response> {
     admin
         .share_files(token, files)
         .await
         .map(|()| "SUCCESS")
         .map_err(|err| {
             tracing::error!("Failed to share files: {err:#}");
-            StatusCode::INTERNAL_SERVER_ERROR
+
+            if let Some(QuotaExceeded { message, .. }) = err.downcast_ref() {
+                IntoResponse::into_response((StatusCode::PAYLOAD_TOO_LARGE, message.clone()))
+            } else if let Some(file_exists) = err.downcast_ref::<FileExists>() {
+                IntoResponse::into_response((StatusCode::CONFLICT, file_exists.to_string()))
+            } else {
+                IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR)
+            }
         })
 }
 
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/share/:token/single-file")]
+struct SingleFilePath {
+    token: Token,
+}
+
+#[derive(serde::Deserialize)]
+struct SingleFile {
+    /// Empty to show all files
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    filename: Option<String>,
+}
+
+async fn set_single_file(
+    SingleFilePath { token }: SingleFilePath,
+    Form(SingleFile { filename }): Form<SingleFile>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    admin
+        .set_single_file(&token, filename)
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to set single file: {err:#}");
+            StatusCode::BAD_REQUEST
+        })?;
+
+    Ok(axum::response::Redirect::to(&format!("../{token}")))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/share/:token/reference")]
+struct ShareReferencePath {
+    token: Token,
+}
+
+#[derive(serde::Deserialize)]
+struct NewReference {
+    path: PathBuf,
+}
+
+async fn share_reference(
+    ShareReferencePath { token }: ShareReferencePath,
+    Form(NewReference { path }): Form<NewReference>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    admin.share_reference(&token, &path).await.map_err(|err| {
+        tracing::error!("Failed to share {}: {err:#}", path.display());
+        StatusCode::BAD_REQUEST
+    })?;
+
+    Ok(axum::response::Redirect::to(&format!("../{token}")))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/share/:token/reference/remove")]
+struct RemoveReferencePath {
+    token: Token,
+}
+
+#[derive(serde::Deserialize)]
+struct RemovedReference {
+    name: String,
+}
+
+async fn remove_reference(
+    RemoveReferencePath { token }: RemoveReferencePath,
+    Form(RemovedReference { name }): Form<RemovedReference>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    admin.remove_reference(&token, &name).await.map_err(|err| {
+        tracing::error!("Failed to remove reference: {err:#}");
+        StatusCode::BAD_REQUEST
+    })?;
+
+    Ok(axum::response::Redirect::to(&format!("../../{token}")))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/share/:token/import")]
+struct ShareImportPath {
+    token: Token,
+}
+
+#[derive(serde::Deserialize)]
+struct NewImport {
+    path: PathBuf,
+}
+
+async fn start_import(
+    ShareImportPath { token }: ShareImportPath,
+    Form(NewImport { path }): Form<NewImport>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    admin
+        .start_import(token.clone(), &path)
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to import {}: {err:#}", path.display());
+            StatusCode::BAD_REQUEST
+        })?;
+
+    Ok(axum::response::Redirect::to(&format!("../{token}")))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/share/:token/imports")]
+struct ShareImportsPath {
+    token: Token,
+}
+
+/// The progress of the share's imports, as JSON
+async fn share_imports(
+    ShareImportsPath { token }: ShareImportsPath,
+    admin: axum::Extension<Admin>,
+) -> impl IntoResponse {
+    Json(admin.imports(&token))
+}
+
 #[derive(TypedPath, serde::Deserialize)]
 #[typed_path("/upload/:token")]
 struct UploadPagePath {
@@ -157,10 +655,18 @@ struct UploadPagePath {
 #[derive(askama::Template)]
 #[template(path = "admin_upload.html")]
 struct UploadPage {
+    token: Token,
     name: String,
     expiry: WebTimestamp,
+    details: TokenDetails,
     space_quota: ByteCount,
+    restrictions: UploadRestrictions,
+    encrypted: bool,
+    webhooks: Vec<Webhook>,
+    email_recipients: Vec<String>,
     upload_url: String,
+    qr_code: String,
+    files: Vec<FileEntry>,
 }
 
 async fn current_upload(
@@ -171,19 +677,102 @@ async fn current_upload(
         name,
         expiry,
         space_quota,
+        restrictions,
+        wrapped_key,
+        webhooks,
+        email_recipients,
+        details,
     } = admin.current_upload_config(&token).await.map_err(|err| {
         tracing::error!("{err:#}");
 
         StatusCode::NOT_FOUND
     })?;
 
+    let files = admin.uploaded_files(&token).await.map_err(|err| {
+        tracing::error!("Failed to list uploaded files: {err:#}");
+
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
     let upload_url = admin.config().token_url("upload", &token);
 
+    let qr_code = qr_code(&upload_url)?;
+
     Ok(UploadPage {
+        token,
         name,
         expiry: expiry.into(),
+        details,
         space_quota,
+        restrictions,
+        encrypted: wrapped_key.is_some(),
+        webhooks,
+        email_recipients,
         upload_url,
+        qr_code,
+        files,
+    }
+    .into_response())
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/upload/:token/:filename")]
+struct UploadedFilePath {
+    token: Token,
+    filename: Filename,
+}
+
+/// Downloads an uploaded file, decrypting it if necessary
+async fn uploaded_file(
+    UploadedFilePath { token, filename }: UploadedFilePath,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let OpenedFile { stream, size, .. } = admin
+        .open_uploaded_file(&token, &filename)
+        .await
+        .map_err(|err| {
+            tracing::error!("Could not open uploaded file: {err:#}");
+
+            StatusCode::NOT_FOUND
+        })?;
+
+    let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+
+    // Uploaded files come from untrusted users, so are never shown inline by the admin app
+    Ok((
+        content::headers(&filename.to_string(), &mime, true),
+        axum::TypedHeader(axum::headers::ContentType::from(mime)),
+        axum::TypedHeader(axum::headers::ContentLength(size.0)),
+        axum::body::StreamBody::new(stream),
+    ))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/handout/upload/:token")]
+struct UploadHandoutPath {
+    token: Token,
+}
+
+/// A printable page with the upload's link and QR code
+async fn upload_handout(
+    UploadHandoutPath { token }: UploadHandoutPath,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let UploadConfig { name, expiry, .. } =
+        admin.current_upload_config(&token).await.map_err(|err| {
+            tracing::error!("{err:#}");
+
+            StatusCode::NOT_FOUND
+        })?;
+
+    let url = admin.config().token_url("upload", &token);
+
+    Ok(Handout {
+        kind: "Upload",
+        name,
+        expiry: expiry.into(),
+        qr_code: qr_code(&url)?,
+        url,
     }
     .into_response())
 }
@@ -194,6 +783,105 @@ struct NewUpload {
     name: String,
     expiry: WebTimestamp,
     space_quota: ByteCount,
+    #[serde(default)]
+    allowed_types: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_file_size: Option<u64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_file_count: Option<u64>,
+    #[serde(flatten)]
+    common: NewTokenFields,
+}
+
+/// The notes and tags entered into a form
+#[derive(Default, serde::Deserialize)]
+struct DetailsForm {
+    #[serde(default)]
+    notes: String,
+    #[serde(default)]
+    tags: String,
+}
+
+impl DetailsForm {
+    fn parse(self) -> TokenDetails {
+        TokenDetails {
+            notes: self.notes,
+            tags: form_tags(&self.tags),
+        }
+    }
+}
+
+/// The fields which the forms creating shares, uploads and exchanges have in common
+#[derive(Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewTokenFields {
+    #[serde(flatten)]
+    details: DetailsForm,
+    #[serde(default)]
+    webhook_url: String,
+    #[serde(default)]
+    webhook_secret: String,
+    #[serde(default)]
+    email_recipients: String,
+}
+
+impl NewTokenFields {
+    /// Returns the details, the webhook entered (if any) and the email recipients
+    fn parse(self) -> (TokenDetails, Vec<Webhook>, Vec<String>) {
+        let url = self.webhook_url.trim();
+
+        let webhooks = if url.is_empty() {
+            Vec::new()
+        } else {
+            vec![Webhook {
+                url: url.into(),
+                secret: (!self.webhook_secret.is_empty()).then_some(self.webhook_secret),
+            }]
+        };
+
+        (
+            self.details.parse(),
+            webhooks,
+            form_list(&self.email_recipients),
+        )
+    }
+}
+
+/// Splits a comma separated list entered into a form
+fn form_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Splits comma separated tags entered into a form. Tags are matched ignoring case
+fn form_tags(tags: &str) -> Vec<String> {
+    let mut tags = form_list(&tags.to_lowercase());
+
+    tags.sort();
+    tags.dedup();
+
+    tags
+}
+
+fn empty_string_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    use serde::Deserialize;
+
+    let value = String::deserialize(deserializer)?;
+    let value = value.trim();
+
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        value.parse().map(Some).map_err(serde::de::Error::custom)
+    }
 }
 
 async fn new_upload(
@@ -201,24 +889,256 @@ async fn new_upload(
         name,
         expiry,
         space_quota,
+        allowed_types,
+        max_file_size,
+        max_file_count,
+        common,
     }): Form<NewUpload>,
     admin: axum::Extension<Admin>,
 ) -> Result<impl IntoResponse, StatusCode> {
+    let (details, webhooks, email_recipients) = common.parse();
+
     let new_token = admin
         .new_upload_token(UploadConfig {
             name,
             expiry: expiry.into(),
             space_quota,
+            wrapped_key: None,
+            restrictions: UploadRestrictions {
+                allowed_types: form_list(&allowed_types),
+                max_file_size: max_file_size.map(ByteCount),
+                max_file_count,
+            },
+            details,
+            webhooks,
+            email_recipients,
         })
         .await
         .map_err(|err| {
-            tracing::error!("Failed to create upload token: {err}");
+            tracing::error!("Failed to create upload token: {err:#}");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
     Ok(axum::response::Redirect::to(new_token.as_str()))
 }
 
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/exchange/:token")]
+struct ExchangePagePath {
+    token: Token,
+}
+
+#[derive(askama::Template)]
+#[template(path = "admin_exchange.html")]
+struct ExchangePage {
+    token: Token,
+    name: String,
+    expiry: WebTimestamp,
+    details: TokenDetails,
+    share: Token,
+    upload: Token,
+    exchange_url: String,
+    qr_code: String,
+}
+
+async fn current_exchange(
+    ExchangePagePath { token }: ExchangePagePath,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let ExchangeConfig {
+        name,
+        expiry,
+        share,
+        upload,
+        details,
+    } = admin.current_exchange_config(&token).await.map_err(|err| {
+        tracing::error!("{err:#}");
+
+        StatusCode::NOT_FOUND
+    })?;
+
+    let exchange_url = admin.config().token_url("exchange", &token);
+
+    let qr_code = qr_code(&exchange_url)?;
+
+    Ok(ExchangePage {
+        token,
+        name,
+        expiry: expiry.into(),
+        details,
+        share,
+        upload,
+        exchange_url,
+        qr_code,
+    }
+    .into_response())
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/handout/exchange/:token")]
+struct ExchangeHandoutPath {
+    token: Token,
+}
+
+/// A printable page with the exchange's link and QR code
+async fn exchange_handout(
+    ExchangeHandoutPath { token }: ExchangeHandoutPath,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let ExchangeConfig { name, expiry, .. } =
+        admin.current_exchange_config(&token).await.map_err(|err| {
+            tracing::error!("{err:#}");
+
+            StatusCode::NOT_FOUND
+        })?;
+
+    let url = admin.config().token_url("exchange", &token);
+
+    Ok(Handout {
+        kind: "Exchange",
+        name,
+        expiry: expiry.into(),
+        qr_code: qr_code(&url)?,
+        url,
+    }
+    .into_response())
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewExchangeForm {
+    name: String,
+    expiry: WebTimestamp,
+    space_quota: ByteCount,
+    #[serde(default)]
+    allowed_types: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_file_size: Option<u64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_file_count: Option<u64>,
+    #[serde(flatten)]
+    common: NewTokenFields,
+}
+
+async fn new_exchange(
+    Form(NewExchangeForm {
+        name,
+        expiry,
+        space_quota,
+        allowed_types,
+        max_file_size,
+        max_file_count,
+        common,
+    }): Form<NewExchangeForm>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let (details, webhooks, email_recipients) = common.parse();
+
+    let new_token = admin
+        .new_exchange_token(NewExchange {
+            name,
+            expiry: expiry.into(),
+            space_quota,
+            restrictions: UploadRestrictions {
+                allowed_types: form_list(&allowed_types),
+                max_file_size: max_file_size.map(ByteCount),
+                max_file_count,
+            },
+            details,
+            webhooks,
+            email_recipients,
+        })
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to create exchange token: {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(axum::response::Redirect::to(new_token.as_str()))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/share/:token/details")]
+struct ShareDetailsPath {
+    token: Token,
+}
+
+async fn set_share_details(
+    ShareDetailsPath { token }: ShareDetailsPath,
+    Form(details): Form<DetailsForm>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    admin
+        .set_share_details(&token, details.parse())
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to set share details: {err:#}");
+            StatusCode::NOT_FOUND
+        })?;
+
+    Ok(axum::response::Redirect::to(&format!("../{token}")))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/upload/:token/details")]
+struct UploadDetailsPath {
+    token: Token,
+}
+
+async fn set_upload_details(
+    UploadDetailsPath { token }: UploadDetailsPath,
+    Form(details): Form<DetailsForm>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    admin
+        .set_upload_details(&token, details.parse())
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to set upload details: {err:#}");
+            StatusCode::NOT_FOUND
+        })?;
+
+    Ok(axum::response::Redirect::to(&format!("../{token}")))
+}
+
+#[derive(TypedPath, serde::Deserialize)]
+#[typed_path("/exchange/:token/details")]
+struct ExchangeDetailsPath {
+    token: Token,
+}
+
+async fn set_exchange_details(
+    ExchangeDetailsPath { token }: ExchangeDetailsPath,
+    Form(details): Form<DetailsForm>,
+    admin: axum::Extension<Admin>,
+) -> Result<impl IntoResponse, StatusCode> {
+    admin
+        .set_exchange_details(&token, details.parse())
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to set exchange details: {err:#}");
+            StatusCode::NOT_FOUND
+        })?;
+
+    Ok(axum::response::Redirect::to(&format!("../{token}")))
+}
+
+async fn render_metrics(admin: axum::Extension<Admin>) -> Result<impl IntoResponse, StatusCode> {
+    admin
+        .render_metrics()
+        .await
+        .map(|metrics| {
+            (
+                [(axum::http::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                metrics,
+            )
+        })
+        .map_err(|err| {
+            tracing::error!("Failed to render metrics: {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
 pub async fn run(admin: Admin, shutdown_signal: impl Future<Output = ()>) {
     if admin.config().disable_admin_app {
         shutdown_signal.await;
@@ -233,9 +1153,32 @@ pub async fn run(admin: Admin, shutdown_signal: impl Future<Output = ()>) {
         .typed_get(current_share)
         .route("/share/", post(new_share))
         .typed_post(share_files)
+        .typed_post(set_single_file)
+        .typed_post(set_share_details)
+        .typed_post(share_reference)
+        .typed_post(remove_reference)
+        .typed_post(start_import)
+        .typed_get(share_imports)
+        .typed_get(share_handout)
         .typed_get(current_upload)
+        .typed_get(uploaded_file)
+        .typed_get(upload_handout)
+        .typed_post(set_upload_details)
         .route("/upload/", post(new_upload))
-        .layer(axum::Extension(admin));
+        .typed_get(current_exchange)
+        .typed_get(exchange_handout)
+        .typed_post(set_exchange_details)
+        .route("/exchange/", post(new_exchange))
+        .route("/metrics", get(render_metrics))
+        .merge(health::routes(admin.config(), admin.storage().clone()))
+        .layer(axum::middleware::from_fn({
+            let metrics = admin.metrics().clone();
+            move |request, next| metrics::track_requests(metrics.clone(), "admin", request, next)
+        }))
+        .layer(axum::Extension(admin))
+        .layer(axum::middleware::from_fn(|request, next| {
+            logging::request_span("admin", request, next)
+        }));
 
     tracing::info!("Admin App is listening on {addr}");
 
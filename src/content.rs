@@ -0,0 +1,182 @@
+use axum::http::{
+    header::{self, HeaderName},
+    HeaderValue,
+};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+
+/// Characters which may appear unencoded in an RFC 5987 `filename*` parameter
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Characters which are encoded in a path segment. Only unreserved characters are left as is
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Served files may not load or run anything, even if a browser renders them as a document
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; img-src 'self'; media-src 'self'; style-src 'unsafe-inline'";
+
+/// Whether the browser could run scripts in a file of this type if it were shown inline.
+/// Any XML based type (e.g. `application/atom+xml`) may be rendered as XML
+pub fn is_active(mime: &mime_guess::Mime) -> bool {
+    mime.suffix() == Some(mime_guess::mime::XML)
+        || matches!(
+            (mime.type_().as_str(), mime.subtype().as_str()),
+            ("text", "html" | "javascript" | "xml" | "xsl" | "ecmascript")
+                | ("application", "javascript" | "ecmascript" | "xml")
+                | ("multipart", _)
+        )
+}
+
+/// A `Content-Disposition` header with both an ASCII fallback and the UTF-8 filename
+fn disposition(filename: &str, attachment: bool) -> HeaderValue {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let value = format!(
+        "{}; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        if attachment { "attachment" } else { "inline" },
+        percent_encoding::utf8_percent_encode(filename, ATTR_CHAR)
+    );
+
+    HeaderValue::from_str(&value).unwrap_or(HeaderValue::from_static("attachment"))
+}
+
+/// Headers which make files safe to serve from the same origin as the app's pages. Active
+/// content is always downloaded rather than shown, as are all files if `attachment` is set
+pub fn headers(
+    filename: &str,
+    mime: &mime_guess::Mime,
+    attachment: bool,
+) -> [(HeaderName, HeaderValue); 3] {
+    [
+        (
+            header::CONTENT_DISPOSITION,
+            disposition(filename, attachment || is_active(mime)),
+        ),
+        (
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        ),
+        (
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+        ),
+    ]
+}
+
+/// Encodes a filename so that it can be used as a segment of a URL path
+pub fn encode_path_segment(filename: &str) -> String {
+    percent_encoding::utf8_percent_encode(filename, PATH_SEGMENT).to_string()
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+/// Whether `application/json` is one of the accepted types. Browsers don't list it
+pub fn accepts_json(headers: &axum::http::HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            media_range
+                .split(';')
+                .next()
+                .is_some_and(|media_type| media_type.trim() == "application/json")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_active_type(mime: &str) -> bool {
+        is_active(&mime.parse().unwrap())
+    }
+
+    #[test]
+    fn xml_based_types_are_active() {
+        for mime in [
+            "image/svg+xml",
+            "application/xhtml+xml",
+            "application/atom+xml",
+            "application/rss+xml",
+            "application/mathml+xml",
+            "text/xml",
+        ] {
+            assert!(is_active_type(mime), "{mime} should be active");
+        }
+    }
+
+    #[test]
+    fn passive_types_are_not_active() {
+        for mime in [
+            "image/png",
+            "text/plain",
+            "application/pdf",
+            "application/json",
+        ] {
+            assert!(!is_active_type(mime), "{mime} should not be active");
+        }
+    }
+
+    fn header_value(headers: &[(HeaderName, HeaderValue)], name: HeaderName) -> &str {
+        headers
+            .iter()
+            .find(|(header, _)| *header == name)
+            .and_then(|(_, value)| value.to_str().ok())
+            .unwrap_or_else(|| panic!("{name} is missing"))
+    }
+
+    #[test]
+    fn active_files_are_always_downloaded() {
+        for (filename, mime, attachment, disposition) in [
+            ("page.html", "text/html", false, "attachment"),
+            ("image.svg", "image/svg+xml", false, "attachment"),
+            ("feed.atom", "application/atom+xml", false, "attachment"),
+            ("photo.png", "image/png", false, "inline"),
+            ("photo.png", "image/png", true, "attachment"),
+        ] {
+            let headers = headers(filename, &mime.parse().unwrap(), attachment);
+
+            assert_eq!(
+                header_value(&headers, header::CONTENT_DISPOSITION),
+                format!("{disposition}; filename=\"{filename}\"; filename*=UTF-8''{filename}"),
+            );
+            assert_eq!(
+                header_value(&headers, header::X_CONTENT_TYPE_OPTIONS),
+                "nosniff"
+            );
+            assert_eq!(
+                header_value(&headers, header::CONTENT_SECURITY_POLICY),
+                CONTENT_SECURITY_POLICY
+            );
+        }
+    }
+}
@@ -1,19 +1,46 @@
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
     marker::PhantomData,
+    ops::{Bound, Range},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Weak},
 };
 
 use anyhow::{Context, Result};
 use axum::extract::Multipart;
 use futures_util::StreamExt;
-use tokio::io::AsyncWriteExt;
-
-use crate::{timestamp::Timestamp, AppConfig};
+use sha2::Digest;
+use tokio::io::AsyncReadExt;
+use tracing::Instrument;
+
+use crate::{
+    email::Mailer,
+    encryption::{self, DataKey, EncryptingWriter, MasterKey},
+    events::{Event, UploadedFile},
+    import::{self, ImportProgress, ImportState, Imports, SourceFile},
+    metrics::{Metrics, QuotaRejection, Transfer},
+    preview::{self, PreviewKind},
+    references::{self, Reference},
+    storage::{self, join_key, path_key, ByteStream, Entry, ObjectNotFound, ObjectWriter, Storage},
+    throttle::{Throttle, TokenBucket},
+    thumbnails,
+    timestamp::{Timestamp, WebDate},
+    webhooks::{Webhook, Webhooks},
+    AppConfig,
+};
 
 const FILES_DIRECTORY: &str = "files";
+const THUMBNAILS_DIRECTORY: &str = "thumbnails";
 const TOKEN_FILENAME: &str = "token.toml";
+const MANIFEST_FILENAME: &str = "manifest.toml";
+const ACTIVITY_FILENAME: &str = "activity.toml";
+
+/// How much of the start of an uploaded file is inspected to determine its content type
+const SNIFF_LENGTH: usize = 8192;
+
+/// The size of the chunks in which imported files are copied
+const IMPORT_CHUNK_SIZE: usize = 64 * 1024;
 
 fn assert_crypto_secure<R: rand::CryptoRng>(r: R) -> R {
     r
@@ -35,14 +62,35 @@ fn sanitize_path<P: AsRef<Path>>(path: P) -> PathBuf {
     buf
 }
 
-fn create_directory<P: AsRef<Path>>(path: P) -> Result<P> {
-    std::fs::create_dir(path.as_ref())
-        .with_context(|| format!("Failed to create directory {}", path.as_ref().display()))?;
+async fn count_files(storage: &dyn Storage, directory: &str) -> Result<u64> {
+    Ok(storage.list(directory).await?.len() as u64)
+}
+
+/// Creates a file, which is encrypted as it is written if the token has a data key
+async fn create_file(
+    storage: &dyn Storage,
+    key: &str,
+    data_key: Option<&DataKey>,
+) -> Result<Box<dyn ObjectWriter>> {
+    let writer = storage.create(key).await?;
+
+    Ok(match data_key {
+        Some(data_key) => Box::new(EncryptingWriter::new(writer, data_key).await?),
+        None => writer,
+    })
+}
+
+async fn read_stream(mut stream: ByteStream) -> std::io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
 
-    Ok(path)
+    while let Some(data) = stream.next().await {
+        contents.extend_from_slice(&data?);
+    }
+
+    Ok(contents)
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Token(String);
 
 impl Token {
@@ -62,6 +110,16 @@ impl Token {
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+
+    /// When the token was created, to the minute. New tokens start with their creation time
+    pub fn created(&self) -> Option<Timestamp> {
+        Timestamp::parse_filename(&self.0)
+    }
+
+    /// Identifies the token in logs without revealing it, as anyone with the token can use it
+    pub fn log_id(&self) -> String {
+        hex::encode(&sha2::Sha256::digest(self.0.as_bytes())[..6])
+    }
 }
 
 impl fmt::Display for Token {
@@ -70,32 +128,64 @@ impl fmt::Display for Token {
     }
 }
 
+impl serde::Serialize for Token {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for Token {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        let token = String::deserialize(deserializer)?;
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
 
+impl std::str::FromStr for Token {
+    type Err = &'static str;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
         if !token.chars().all(|c| c == '_' || c.is_ascii_alphanumeric()) {
-            return Err(serde::de::Error::custom("Invalid token"));
+            return Err("Invalid token");
         }
 
-        Ok(Self(token))
+        Ok(Self(token.into()))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct ByteCount(pub u64);
 
 impl ByteCount {
-    fn checked_sub(self, rhs: ByteCount) -> Option<Self> {
-        self.0.checked_sub(rhs.0).map(Self)
-    }
-
     fn saturating_sub(self, rhs: ByteCount) -> Self {
         Self(self.0.saturating_sub(rhs.0))
     }
+
+    /// The size in the largest binary unit in which it is at least 1, e.g. `1.5 MiB`
+    pub fn human(&self) -> String {
+        const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        if self.0 < 1024 {
+            return format!("{} bytes", self.0);
+        }
+
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+
+        format!("{size:.1} {}", UNITS[unit])
+    }
 }
 
 impl fmt::Display for ByteCount {
@@ -128,60 +218,375 @@ impl serde::Serialize for ByteCount {
     }
 }
 
+/// A token which doesn't exist or has expired, as opposed to a failure to use a token
+#[derive(Debug)]
+pub struct TokenNotFound;
+
+impl fmt::Display for TokenNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "Token not found".fmt(f)
+    }
+}
+
+impl std::error::Error for TokenNotFound {}
+
+/// A file which exists, but can't be previewed or have a thumbnail generated
+#[derive(Debug)]
+pub struct UnsupportedFile;
+
+impl fmt::Display for UnsupportedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "Unsupported file".fmt(f)
+    }
+}
+
+impl std::error::Error for UnsupportedFile {}
+
+/// An upload of a file with the same name as one which is already stored
+#[derive(Debug)]
+pub struct FileExists(pub String);
+
+impl fmt::Display for FileExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already exists", self.0)
+    }
+}
+
+impl std::error::Error for FileExists {}
+
+/// An upload which was rejected by a quota or restriction
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub rejection: QuotaRejection,
+    /// Why the upload was rejected, which can be shown to the user
+    pub message: String,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+fn quota_exceeded(rejection: QuotaRejection, message: impl Into<String>) -> anyhow::Error {
+    QuotaExceeded {
+        rejection,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// The quota of an upload token which uploads in progress may still reserve
+struct TokenSpace {
+    remaining: ByteCount,
+    uploads: usize,
+}
+
+/// Where files are stored
+#[derive(Clone, Copy)]
+enum StorageArea {
+    Shares,
+    Uploads,
+}
+
+/// The size of the shares and uploads directories
+#[derive(Clone, Copy, Default)]
+struct StoredSize {
+    shares: ByteCount,
+    uploads: ByteCount,
+}
+
+impl StoredSize {
+    fn total(&self) -> ByteCount {
+        let mut total = self.shares;
+        total += self.uploads;
+
+        total
+    }
+
+    fn area_mut(&mut self, area: StorageArea) -> &mut ByteCount {
+        match area {
+            StorageArea::Shares => &mut self.shares,
+            StorageArea::Uploads => &mut self.uploads,
+        }
+    }
+}
+
+/// Space reserved by uploads and imports in progress, so that they can't together exceed a
+/// quota. A std mutex guards it, so that reservations can be released as uploads are dropped
+#[derive(Default)]
+struct SpaceLedger {
+    /// By token key. Removed when no uploads to the token are in progress
+    tokens: HashMap<String, TokenSpace>,
+    /// Found when first needed, then kept up to date as files are stored, so that storage
+    /// needn't be walked for every upload. Files which aren't uploaded or imported, such as
+    /// configs and thumbnails, are only counted when it is found
+    stored: Option<StoredSize>,
+    /// Counts files being stored, so that a size found while files are being stored isn't kept
+    changes: u64,
+    /// Reserved by uploads and imports in progress, but not yet stored
+    reserved: ByteCount,
+    /// Everything ever reserved, so that a reservation can tell how much has been reserved
+    /// since it started
+    reservations: ByteCount,
+}
+
+type SpaceLedgerMutex = std::sync::Mutex<SpaceLedger>;
+
+/// Space which an upload or import has reserved as it writes files. Whatever wasn't stored is
+/// returned when it ends, or if it is dropped
+struct SpaceReservation<'a> {
+    ledger: &'a SpaceLedgerMutex,
+    area: StorageArea,
+    /// The upload token whose quota is reserved from, if any
+    token_key: Option<String>,
+    reserved: ByteCount,
+    storage_quota: Option<ByteCount>,
+    /// Used if the ledger's stored size couldn't be kept, as files were stored while it was found
+    stored_at_start: StoredSize,
+    /// The space which was available when the reservation started, less the free space reserve
+    free_space: Option<ByteCount>,
+    /// `SpaceLedger::reservations` when the reservation started
+    reservations_at_start: ByteCount,
+}
+
+impl<'a> SpaceReservation<'a> {
+    /// Reserves from the quota of an upload token as well. Must be called while the token
+    /// config is locked, so that the quota in the ledger agrees with the token config
+    fn with_token(mut self, token_key: &str, space_quota: ByteCount) -> Self {
+        self.ledger
+            .lock()
+            .unwrap()
+            .tokens
+            .entry(token_key.into())
+            .or_insert(TokenSpace {
+                remaining: space_quota,
+                uploads: 0,
+            })
+            .uploads += 1;
+
+        self.token_key = Some(token_key.into());
+
+        self
+    }
+
+    /// The space which may still be reserved before either the storage quota or the free space
+    /// reserve is reached
+    fn storage_space_remaining(&self) -> ByteCount {
+        let ledger = self.ledger.lock().unwrap();
+
+        let mut remaining = ByteCount(u64::MAX);
+
+        if let Some(free_space) = self.free_space {
+            let reserved_since_start = ledger
+                .reservations
+                .saturating_sub(self.reservations_at_start);
+
+            remaining = remaining.min(free_space.saturating_sub(reserved_since_start));
+        }
+
+        if let Some(storage_quota) = self.storage_quota {
+            let mut used = ledger.stored.unwrap_or(self.stored_at_start).total();
+            used += ledger.reserved;
+
+            remaining = remaining.min(storage_quota.saturating_sub(used));
+        }
+
+        remaining
+    }
+
+    /// The space of the token's quota which may still be reserved
+    fn token_space_remaining(&self) -> Option<ByteCount> {
+        let token_key = self.token_key.as_ref()?;
+
+        Some(self.ledger.lock().unwrap().tokens[token_key].remaining)
+    }
+
+    /// Reserves space for data before it is written
+    fn write(&mut self, size: ByteCount) -> Result<()> {
+        if size > self.storage_space_remaining() {
+            return Err(quota_exceeded(QuotaRejection::Space, "Storage is full"));
+        }
+
+        let mut ledger = self.ledger.lock().unwrap();
+
+        if let Some(token_key) = &self.token_key {
+            let token_space = ledger
+                .tokens
+                .get_mut(token_key)
+                .context("Space reservation is missing")?;
+
+            if size > token_space.remaining {
+                return Err(quota_exceeded(QuotaRejection::Space, "Out of Space"));
+            }
+
+            token_space.remaining = token_space.remaining.saturating_sub(size);
+        }
+
+        ledger.reserved += size;
+        ledger.reservations += size;
+        self.reserved += size;
+
+        Ok(())
+    }
+
+    /// Records that files have been stored, so that their space is no longer returned. If the
+    /// reservation has a token, must be called while the token config is locked, as the stored
+    /// size must be taken from its quota
+    fn store(&mut self, stored: ByteCount) {
+        let stored = stored.min(self.reserved);
+
+        let mut ledger = self.ledger.lock().unwrap();
+
+        ledger.reserved = ledger.reserved.saturating_sub(stored);
+        ledger.changes += 1;
+
+        let area = self.area;
+
+        if let Some(stored_size) = &mut ledger.stored {
+            *stored_size.area_mut(area) += stored;
+        }
+
+        self.reserved = self.reserved.saturating_sub(stored);
+    }
+}
+
+impl Drop for SpaceReservation<'_> {
+    fn drop(&mut self) {
+        let mut ledger = self.ledger.lock().unwrap();
+
+        ledger.reserved = ledger.reserved.saturating_sub(self.reserved);
+
+        if let Some(token_key) = &self.token_key {
+            if let Some(token_space) = ledger.tokens.get_mut(token_key) {
+                token_space.remaining += self.reserved;
+                token_space.uploads -= 1;
+
+                if token_space.uploads == 0 {
+                    ledger.tokens.remove(token_key);
+                }
+            }
+        }
+    }
+}
+
+/// The files in a directory which files are being added to. Replacing a file would count it
+/// twice towards quotas, and leave its thumbnail out of date, so names can't be reused
+struct ExistingFiles(HashSet<String>);
+
+impl ExistingFiles {
+    async fn list(storage: &dyn Storage, directory: &str) -> Result<Self> {
+        Ok(Self(
+            storage
+                .list(directory)
+                .await?
+                .into_iter()
+                .map(|entry| match entry {
+                    Entry::File { name, .. } | Entry::Directory { name } => name,
+                })
+                .collect(),
+        ))
+    }
+
+    fn contains(&self, file_name: &Path) -> bool {
+        self.0.contains(&path_key(file_name))
+    }
+
+    /// Records a file which is about to be added, if the limit on the number of files allows it
+    fn add(&mut self, file_name: &Path, max_file_count: Option<u64>) -> Result<()> {
+        if let Some(max_file_count) = max_file_count {
+            if self.0.len() as u64 >= max_file_count {
+                return Err(quota_exceeded(
+                    QuotaRejection::FileCount,
+                    format!("Too many files: at most {max_file_count} files may be uploaded"),
+                ));
+            }
+        }
+
+        if !self.0.insert(path_key(file_name)) {
+            return Err(FileExists(file_name.display().to_string()).into());
+        }
+
+        Ok(())
+    }
+}
+
 struct NewFile<'a> {
     filename: &'a Path,
-    file: Option<tokio::fs::File>,
+    writer: Box<dyn ObjectWriter>,
     size: ByteCount,
+    max_size: Option<ByteCount>,
+    /// The checksum of the contents, before any encryption
+    sha256: sha2::Sha256,
 }
 
 impl<'a> NewFile<'a> {
-    async fn new(filename: &'a Path) -> Result<NewFile<'a>> {
-        let file = Some(
-            tokio::fs::File::create(filename)
-                .await
-                .with_context(|| format!("Failed to create {}", filename.display()))?,
-        );
-
+    async fn new(
+        storage: &dyn Storage,
+        key: &str,
+        filename: &'a Path,
+        max_size: Option<ByteCount>,
+        data_key: Option<&DataKey>,
+    ) -> Result<NewFile<'a>> {
         Ok(Self {
             filename,
-            file,
+            writer: create_file(storage, key, data_key).await?,
             size: ByteCount(0),
+            max_size,
+            sha256: sha2::Sha256::new(),
         })
     }
 
-    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
-        self.file
-            .as_mut()
-            .unwrap()
-            .write_all(data)
-            .await
-            .with_context(|| format!("Failed to write to {}", self.filename.display()))?;
+    async fn write_all(
+        &mut self,
+        data: &[u8],
+        reservation: Option<&mut SpaceReservation<'_>>,
+    ) -> Result<()> {
+        let new_size = ByteCount(self.size.0 + data.len() as u64);
+
+        if let Some(max_size) = self.max_size {
+            if new_size > max_size {
+                return Err(quota_exceeded(
+                    QuotaRejection::FileSize,
+                    format!("{} exceeds {max_size} bytes", self.filename.display()),
+                ));
+            }
+        }
+
+        if let Some(reservation) = reservation {
+            reservation.write(ByteCount(data.len() as u64))?;
+        }
+
+        self.writer.write(data).await?;
 
-        self.size.0 += data.len() as u64;
+        self.sha256.update(data);
+        self.size = new_size;
 
         Ok(())
     }
 
-    async fn close(mut self) -> Result<ByteCount> {
-        self.file
-            .as_mut()
-            .unwrap()
-            .flush()
-            .await
-            .with_context(|| format!("Failed to flush {}", self.filename.display()))?;
-
-        self.file.take();
+    /// Finishes writing the file, returning its size and checksum
+    async fn close(self) -> Result<(ByteCount, String)> {
+        self.writer.finish().await?;
 
         tracing::debug!("Finished writing to {}", self.filename.display());
 
-        Ok(self.size)
+        Ok((self.size, hex::encode(self.sha256.finalize())))
     }
 
     async fn from_multipart(
-        storage_directory: PathBuf,
+        storage: &dyn Storage,
+        files_key: &str,
         mut files: Multipart,
-        total_size: &mut ByteCount,
+        uploaded_files: &mut Vec<UploadedFile>,
+        reservation: &mut SpaceReservation<'_>,
+        restrictions: &UploadRestrictions,
+        data_key: Option<&DataKey>,
     ) -> Result<()> {
+        let mut existing_files = ExistingFiles::list(storage, files_key).await?;
+
         while let Some(mut field) = files
             .next_field()
             .await
@@ -192,49 +597,135 @@ impl<'a> NewFile<'a> {
                 None => continue,
             };
 
-            let file_path = storage_directory.join(sanitize_path(&file_name));
+            let file_name = sanitize_path(&file_name);
 
-            tracing::info!("Uploading to {}", file_path.display());
+            if file_name.components().count() != 1 {
+                anyhow::bail!("Bad filename {}", file_name.display());
+            }
+
+            existing_files.add(&file_name, restrictions.max_file_count)?;
+
+            let file_key = join_key(files_key, &path_key(&file_name));
+
+            tracing::info!("Uploading {}", file_name.display());
+
+            let mut file = NewFile::new(
+                storage,
+                &file_key,
+                &file_name,
+                restrictions.max_file_size,
+                data_key,
+            )
+            .await?;
+
+            if !restrictions.allowed_types.is_empty() {
+                let mut header = Vec::new();
+
+                while header.len() < SNIFF_LENGTH {
+                    match field.next().await {
+                        Some(blob) => {
+                            header.extend_from_slice(&blob.context("Failed to read data")?)
+                        }
+                        None => break,
+                    }
+                }
+
+                restrictions.check_file_type(&file_name, &header)?;
 
-            let mut file = NewFile::new(&file_path).await?;
+                file.write_all(&header, Some(reservation)).await?;
+            }
 
             while let Some(blob) = field.next().await {
                 let blob = blob.context("Failed to read data")?;
-                file.write_all(&blob).await?;
+                file.write_all(&blob, Some(reservation)).await?;
             }
 
-            *total_size += file.close().await?;
+            let (size, sha256) = file.close().await?;
+
+            uploaded_files.push(UploadedFile {
+                name: file_name.display().to_string(),
+                size,
+                sha256,
+            });
 
-            tracing::debug!("Finished uploading to {}", file_path.display());
+            tracing::debug!("Finished uploading {}", file_name.display());
         }
 
         Ok(())
     }
 }
 
-impl<'a> Drop for NewFile<'a> {
-    fn drop(&mut self) {
-        if self.file.take().is_some() {
-            if let Err(err) = std::fs::remove_file(self.filename) {
-                tracing::error!("Failed to remove {}: {}", self.filename.display(), err);
-            }
-        }
+fn total_size(files: &[UploadedFile]) -> ByteCount {
+    let mut total_size = ByteCount(0);
+
+    for file in files {
+        total_size += file.size;
     }
+
+    total_size
 }
 
 trait IsTokenConfig: serde::Serialize + serde::de::DeserializeOwned {
-    fn storage_directory(config: &AppConfig) -> PathBuf;
+    fn storage_key(config: &AppConfig) -> String;
+
+    fn wrapped_key_mut(&mut self) -> &mut Option<String>;
+}
+
+/// What the admin records about a share, upload or exchange. Never shown to users
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct TokenDetails {
+    /// For the admin's reference
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+    /// Matched ignoring case, so always lowercase
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct ShareConfig {
     pub name: String,
     pub expiry: Timestamp,
+    /// The maximum total download rate of the share, in bytes per second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_rate_limit: Option<ByteCount>,
+    /// The key which the share's files are encrypted with, encrypted with the master key.
+    /// `None` if the files are not encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<String>,
+    /// Files are encrypted and decrypted by the browser, with a key which the server never sees
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub end_to_end: bool,
+    #[serde(flatten)]
+    pub details: TokenDetails,
+    /// Emailed when files are downloaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub email_recipients: Vec<String>,
+    /// The only file which can be downloaded, in place of a listing of all files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub single_file: Option<String>,
+    // TOML requires tables to follow all other values
+    /// Notified when files are downloaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<Webhook>,
+}
+
+impl ShareConfig {
+    /// Whether users can download the file. Single file shares only show their one file
+    fn is_visible(&self, filename: &Filename) -> bool {
+        self.single_file
+            .as_ref()
+            .is_none_or(|single_file| *single_file == filename.to_string())
+    }
 }
 
 impl IsTokenConfig for ShareConfig {
-    fn storage_directory(config: &AppConfig) -> PathBuf {
-        config.shares_directory()
+    fn storage_key(config: &AppConfig) -> String {
+        config.shares_key()
+    }
+
+    fn wrapped_key_mut(&mut self) -> &mut Option<String> {
+        &mut self.wrapped_key
     }
 }
 
@@ -243,458 +734,3238 @@ pub struct UploadConfig {
     pub name: String,
     pub expiry: Timestamp,
     pub space_quota: ByteCount,
+    #[serde(flatten)]
+    pub details: TokenDetails,
+    /// Emailed when uploads complete
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub email_recipients: Vec<String>,
+    /// The key which uploaded files are encrypted with, encrypted with the master key.
+    /// `None` if the files are not encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<String>,
+    // TOML requires tables to follow all other values
+    #[serde(default)]
+    pub restrictions: UploadRestrictions,
+    /// Notified when uploads complete
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<Webhook>,
 }
 
-impl IsTokenConfig for UploadConfig {
-    fn storage_directory(config: &AppConfig) -> PathBuf {
-        config.uploads_directory()
-    }
+/// Limits on what may be uploaded to an upload token, in addition to the space quota
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct UploadRestrictions {
+    /// File extensions (e.g. ".pdf") and MIME types (e.g. "image/png" or "image/*").
+    /// If empty, all file types are allowed
+    #[serde(default)]
+    pub allowed_types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<ByteCount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_count: Option<u64>,
 }
 
-struct TokenConfigMutexCore;
-
-impl TokenConfigMutexCore {
-    fn load_config<C: serde::de::DeserializeOwned>(token_directory: &Path) -> Result<C> {
-        let path = token_directory.join(TOKEN_FILENAME);
+impl UploadRestrictions {
+    fn allowed_mime_types(&self) -> impl Iterator<Item = mime_guess::Mime> + '_ {
+        self.allowed_types.iter().flat_map(|allowed_type| {
+            let allowed_type = allowed_type.trim();
+
+            if allowed_type.contains('/') {
+                allowed_type.parse().ok().into_iter().collect::<Vec<_>>()
+            } else {
+                mime_guess::from_ext(allowed_type.trim_start_matches('.'))
+                    .iter()
+                    .collect()
+            }
+        })
+    }
 
-        tracing::debug!(path = %path.display(), "Loading token config");
+    fn is_allowed_mime_type(&self, mime: &mime_guess::Mime) -> bool {
+        self.allowed_mime_types().any(|allowed_mime| {
+            allowed_mime.type_() == mime.type_()
+                && (allowed_mime.subtype() == mime_guess::mime::STAR
+                    || allowed_mime.subtype() == mime.subtype())
+        })
+    }
 
-        let file_contents = std::fs::read_to_string(&path)
-            .with_context(|| format!("Failed to read {}", path.display()))?;
-        toml::from_str::<C>(&file_contents)
-            .with_context(|| format!("Failed to parse {}", file_contents))
+    /// Rejects a file, telling the uploader which types they may upload instead
+    fn type_not_allowed(&self, reason: String) -> anyhow::Error {
+        quota_exceeded(
+            QuotaRejection::FileType,
+            format!("{reason}. Allowed types: {}", self.allowed_types.join(", ")),
+        )
     }
 
-    fn save_config<C: serde::Serialize>(token_directory: &Path, config: &C) -> Result<()> {
-        let path = token_directory.join(TOKEN_FILENAME);
+    /// Checks both the type implied by the filename and the type implied by the file contents.
+    /// Files of types which can be recognised by their contents must contain that type
+    fn check_file_type(&self, path: &Path, header: &[u8]) -> Result<()> {
+        let declared_mime = mime_guess::from_path(path).first_or_octet_stream();
+
+        if !self.is_allowed_mime_type(&declared_mime) {
+            return Err(self.type_not_allowed(format!(
+                "{} has type {declared_mime}, which is not allowed",
+                path.display()
+            )));
+        }
 
-        tracing::debug!(path = %path.display(), "Saving token config");
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
 
-        std::fs::write(
-            &path,
-            toml::to_string(config).context("Failed to serialize config")?,
-        )
-        .with_context(|| format!("Failed to write config to {}", path.display()))
+        let is_recognisable = infer::is_supported(&extension)
+            || infer::is_mime_supported(declared_mime.essence_str());
+
+        if is_recognisable
+            && !infer::is(header, &extension)
+            && !infer::is_mime(header, declared_mime.essence_str())
+        {
+            return Err(self.type_not_allowed(format!(
+                "{} does not contain {declared_mime}",
+                path.display()
+            )));
+        }
+
+        if let Some(sniffed_type) = infer::get(header) {
+            let sniffed_mime = sniffed_type
+                .mime_type()
+                .parse()
+                .with_context(|| format!("Bad MIME type {}", sniffed_type.mime_type()))?;
+
+            if !self.is_allowed_mime_type(&sniffed_mime) {
+                return Err(self.type_not_allowed(format!(
+                    "{} contains {sniffed_mime}, which is not allowed",
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(())
     }
+}
 
-    fn create_token_config<C: serde::Serialize>(
-        &mut self,
-        token_directory: &Path,
-        config: &C,
-    ) -> Result<()> {
-        create_directory(token_directory)?;
-        create_directory(token_directory.join(FILES_DIRECTORY))?;
-        Self::save_config(token_directory, config)
+impl IsTokenConfig for UploadConfig {
+    fn storage_key(config: &AppConfig) -> String {
+        config.uploads_key()
     }
 
-    fn token_config<C: serde::de::DeserializeOwned>(
-        &mut self,
-        token_directory: &Path,
-    ) -> Result<C> {
-        Self::load_config(token_directory)
+    fn wrapped_key_mut(&mut self) -> &mut Option<String> {
+        &mut self.wrapped_key
     }
+}
 
-    fn with_token_config_mut<
-        C: serde::Serialize + serde::de::DeserializeOwned,
-        T,
-        F: FnOnce(&mut C) -> Result<T>,
-    >(
-        &mut self,
-        token_directory: &Path,
-        f: F,
-    ) -> Result<T> {
-        let mut config = Self::load_config(token_directory)?;
+/// A share and an upload token which are offered together on one page, so that files can be
+/// sent in both directions with a single link. The share and upload token keep their own
+/// files and quota, and are managed from their own admin pages
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExchangeConfig {
+    pub name: String,
+    pub expiry: Timestamp,
+    /// The share of files provided to the recipient
+    pub share: Token,
+    /// The upload token which receives the recipient's replies
+    pub upload: Token,
+    #[serde(flatten)]
+    pub details: TokenDetails,
+}
 
-        let result = f(&mut config)?;
+/// A token config which is listed, and can be searched, in the admin app
+trait ListedTokenConfig: serde::Serialize + serde::de::DeserializeOwned {
+    /// Whether the token stores files of its own
+    const HAS_FILES: bool;
 
-        Self::save_config(token_directory, &config)?;
+    fn listing(self, token: Token) -> TokenListing;
 
-        Ok(result)
-    }
+    fn details_mut(&mut self) -> &mut TokenDetails;
 }
 
-type TokenConfigMutex = tokio::sync::Mutex<TokenConfigMutexCore>;
+impl ListedTokenConfig for ShareConfig {
+    const HAS_FILES: bool = true;
 
-struct TokenConfig<'a, C> {
-    token_directory: PathBuf,
-    token_config_mutex: &'a TokenConfigMutex,
-    _config: PhantomData<C>,
+    fn listing(self, token: Token) -> TokenListing {
+        TokenListing::new(token, self.name, self.expiry, self.details)
+    }
+
+    fn details_mut(&mut self) -> &mut TokenDetails {
+        &mut self.details
+    }
 }
 
-impl<'a, C: serde::Serialize + serde::de::DeserializeOwned> TokenConfig<'a, C> {
-    fn new(token_directory: PathBuf, token_config_mutex: &'a TokenConfigMutex) -> Self {
-        Self {
-            token_directory,
-            token_config_mutex,
-            _config: PhantomData,
+impl ListedTokenConfig for UploadConfig {
+    const HAS_FILES: bool = true;
+
+    fn listing(self, token: Token) -> TokenListing {
+        TokenListing {
+            quota_remaining: Some(self.space_quota),
+            ..TokenListing::new(token, self.name, self.expiry, self.details)
         }
     }
 
-    fn files_directory(&self) -> PathBuf {
-        self.token_directory.join(FILES_DIRECTORY)
+    fn details_mut(&mut self) -> &mut TokenDetails {
+        &mut self.details
     }
+}
 
-    async fn create(&self, config: &C) -> Result<()> {
-        self.token_config_mutex
-            .lock()
-            .await
-            .create_token_config(&self.token_directory, config)
+impl ListedTokenConfig for ExchangeConfig {
+    const HAS_FILES: bool = false;
+
+    fn listing(self, token: Token) -> TokenListing {
+        TokenListing::new(token, self.name, self.expiry, self.details)
     }
 
-    async fn load(&self) -> Result<C> {
-        self.token_config_mutex
-            .lock()
-            .await
-            .token_config(&self.token_directory)
+    fn details_mut(&mut self) -> &mut TokenDetails {
+        &mut self.details
     }
+}
 
-    async fn update<T, F: FnOnce(&mut C) -> Result<T>>(&self, f: F) -> Result<T> {
-        self.token_config_mutex
-            .lock()
-            .await
-            .with_token_config_mut(&self.token_directory, f)
+impl<C: ListedTokenConfig> TokenConfig<'_, C> {
+    async fn set_details(&self, details: TokenDetails) -> Result<()> {
+        self.update(|config| {
+            *config.details_mut() = details;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// What is known about a token's files beyond what storage records
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    files: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ManifestEntry {
+    /// The SHA-256 checksum of the file's contents, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// A file on the server which is shared in place of a file in storage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reference: Option<PathBuf>,
+}
+
+/// How many files a token has, and how much space they take up
+#[derive(Clone, Copy, Default)]
+pub struct TokenUsage {
+    /// Includes files shared by reference
+    pub file_count: u64,
+    /// The size of the files in storage, which files shared by reference aren't in
+    pub stored_size: ByteCount,
+}
+
+/// How a token has been used by users
+#[derive(Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+pub struct TokenActivity {
+    /// How many times files have been completely downloaded
+    #[serde(default)]
+    pub downloads: u64,
+    /// When a user last viewed the token's page or downloaded its files, to the minute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_accessed: Option<Timestamp>,
+}
+
+/// Along with guarding token configs, caches what is costly to find for every token whenever
+/// the admin app lists them
+#[derive(Default)]
+struct TokenConfigMutexCore {
+    /// By token key. Removed whenever the token's files change
+    usage: HashMap<String, TokenUsage>,
+    /// Counts changes to files, so that usage found while files are changing isn't cached
+    file_changes: u64,
+}
+
+impl TokenConfigMutexCore {
+    async fn load_config<C: serde::de::DeserializeOwned>(
+        storage: &dyn Storage,
+        token_key: &str,
+    ) -> Result<C> {
+        let key = join_key(token_key, TOKEN_FILENAME);
+
+        tracing::debug!("Loading token config");
+
+        let file_contents = String::from_utf8(storage.read(&key).await?)
+            .with_context(|| format!("{key} is not UTF-8"))?;
+        toml::from_str::<C>(&file_contents).with_context(|| format!("Failed to parse {key}"))
+    }
+
+    async fn save_config<C: serde::Serialize>(
+        storage: &dyn Storage,
+        token_key: &str,
+        config: &C,
+    ) -> Result<()> {
+        let key = join_key(token_key, TOKEN_FILENAME);
+
+        tracing::debug!("Saving token config");
+
+        storage
+            .write(
+                &key,
+                toml::to_string(config)
+                    .context("Failed to serialize config")?
+                    .into_bytes(),
+            )
+            .await
+            .with_context(|| format!("Failed to write config to {key}"))
+    }
+
+    async fn create_token_config<C: serde::Serialize>(
+        &mut self,
+        storage: &dyn Storage,
+        token_key: &str,
+        config: &C,
+    ) -> Result<()> {
+        storage
+            .create_directory(&join_key(token_key, FILES_DIRECTORY))
+            .await?;
+        Self::save_config(storage, token_key, config).await
+    }
+
+    /// For tokens which have no files of their own
+    async fn create_bare_token_config<C: serde::Serialize>(
+        &mut self,
+        storage: &dyn Storage,
+        token_key: &str,
+        config: &C,
+    ) -> Result<()> {
+        storage.create_directory(token_key).await?;
+        Self::save_config(storage, token_key, config).await
+    }
+
+    async fn token_config<C: serde::de::DeserializeOwned>(
+        &mut self,
+        storage: &dyn Storage,
+        token_key: &str,
+    ) -> Result<C> {
+        Self::load_config(storage, token_key).await
+    }
+
+    /// Whether the token has a file of its own, beside its config, such as its manifest
+    async fn exists(storage: &dyn Storage, token_key: &str, filename: &str) -> Result<bool> {
+        Ok(storage
+            .list(token_key)
+            .await?
+            .iter()
+            .any(|entry| matches!(entry, Entry::File { name, .. } if name == filename)))
+    }
+
+    /// Tokens created before manifests were added, or with no uploaded files, have none
+    async fn load_manifest(storage: &dyn Storage, token_key: &str) -> Result<Manifest> {
+        if !Self::exists(storage, token_key, MANIFEST_FILENAME).await? {
+            return Ok(Manifest::default());
+        }
+
+        let key = join_key(token_key, MANIFEST_FILENAME);
+
+        let file_contents = String::from_utf8(storage.read(&key).await?)
+            .with_context(|| format!("{key} is not UTF-8"))?;
+        toml::from_str(&file_contents).with_context(|| format!("Failed to parse {key}"))
+    }
+
+    async fn manifest(&mut self, storage: &dyn Storage, token_key: &str) -> Result<Manifest> {
+        Self::load_manifest(storage, token_key).await
+    }
+
+    async fn with_manifest_mut<T, F: FnOnce(&mut Manifest) -> Result<T>>(
+        &mut self,
+        storage: &dyn Storage,
+        token_key: &str,
+        f: F,
+    ) -> Result<T> {
+        let mut manifest = Self::load_manifest(storage, token_key).await?;
+
+        let result = f(&mut manifest)?;
+
+        self.files_changed(token_key);
+
+        let key = join_key(token_key, MANIFEST_FILENAME);
+
+        storage
+            .write(
+                &key,
+                toml::to_string(&manifest)
+                    .context("Failed to serialize manifest")?
+                    .into_bytes(),
+            )
+            .await
+            .with_context(|| format!("Failed to write manifest to {key}"))?;
+
+        Ok(result)
+    }
+
+    fn files_changed(&mut self, token_key: &str) {
+        self.usage.remove(token_key);
+        self.file_changes += 1;
+    }
+
+    /// The cached usage, if any, and the count of file changes to cache newly found usage with
+    fn cached_usage(&self, token_key: &str) -> (Option<TokenUsage>, u64) {
+        (self.usage.get(token_key).copied(), self.file_changes)
+    }
+
+    /// Caches usage, unless files have changed since it started being found
+    fn cache_usage(&mut self, token_key: &str, usage: TokenUsage, file_changes: u64) {
+        if file_changes == self.file_changes {
+            self.usage.insert(token_key.into(), usage);
+        }
+    }
+
+    async fn with_token_config_mut<
+        C: serde::Serialize + serde::de::DeserializeOwned,
+        T,
+        F: FnOnce(&mut C) -> Result<T>,
+    >(
+        &mut self,
+        storage: &dyn Storage,
+        token_key: &str,
+        f: F,
+    ) -> Result<T> {
+        let mut config = Self::load_config(storage, token_key).await?;
+
+        let result = f(&mut config)?;
+
+        Self::save_config(storage, token_key, &config).await?;
+
+        Ok(result)
+    }
+}
+
+type TokenConfigMutex = tokio::sync::Mutex<TokenConfigMutexCore>;
+
+/// Kept apart from the token config lock, so that saving activity doesn't hold up other requests
+#[derive(Default)]
+struct ActivityCore {
+    /// By token key. Loaded when first needed, then kept up to date as activity is recorded
+    activity: HashMap<String, TokenActivity>,
+    /// Tokens whose activity has changed since it was last saved
+    unsaved: HashSet<String>,
+    /// Tokens whose activity is being saved. Only one save of a token runs at a time, which
+    /// also saves changes made while it runs
+    saving: HashSet<String>,
+}
+
+impl ActivityCore {
+    async fn load(storage: &dyn Storage, token_key: &str) -> Result<TokenActivity> {
+        // Tokens which haven't been used since activity was recorded have none
+        if !TokenConfigMutexCore::exists(storage, token_key, ACTIVITY_FILENAME).await? {
+            return Ok(TokenActivity::default());
+        }
+
+        let key = join_key(token_key, ACTIVITY_FILENAME);
+
+        let file_contents = String::from_utf8(storage.read(&key).await?)
+            .with_context(|| format!("{key} is not UTF-8"))?;
+        toml::from_str(&file_contents).with_context(|| format!("Failed to parse {key}"))
+    }
+
+    async fn save(storage: &dyn Storage, token_key: &str, activity: &TokenActivity) -> Result<()> {
+        let key = join_key(token_key, ACTIVITY_FILENAME);
+
+        storage
+            .write(
+                &key,
+                toml::to_string(activity)
+                    .context("Failed to serialize activity")?
+                    .into_bytes(),
+            )
+            .await
+            .with_context(|| format!("Failed to write activity to {key}"))
+    }
+}
+
+type ActivityMutex = std::sync::Mutex<ActivityCore>;
+
+/// The save of a token's activity which is in progress
+struct ActivitySave<'a> {
+    activity_mutex: &'a ActivityMutex,
+    token_key: &'a str,
+    finished: bool,
+}
+
+impl ActivitySave<'_> {
+    /// The activity to save next, or `None` once all changes have been saved
+    fn next(&mut self) -> Option<TokenActivity> {
+        let mut core = self.activity_mutex.lock().unwrap();
+
+        if core.unsaved.remove(self.token_key) {
+            return core.activity.get(self.token_key).copied();
+        }
+
+        core.saving.remove(self.token_key);
+        self.finished = true;
+
+        None
+    }
+}
+
+impl Drop for ActivitySave<'_> {
+    /// If saving failed or was cancelled, the activity is saved when the token is next used
+    fn drop(&mut self) {
+        if !self.finished {
+            let mut core = self.activity_mutex.lock().unwrap();
+
+            core.saving.remove(self.token_key);
+            core.unsaved.insert(self.token_key.into());
+        }
+    }
+}
+
+struct TokenConfig<'a, C> {
+    storage: &'a dyn Storage,
+    token_key: String,
+    token_config_mutex: &'a TokenConfigMutex,
+    activity_mutex: &'a ActivityMutex,
+    _config: PhantomData<C>,
+}
+
+impl<'a, C: serde::Serialize + serde::de::DeserializeOwned> TokenConfig<'a, C> {
+    fn new(
+        storage: &'a dyn Storage,
+        token_key: String,
+        token_config_mutex: &'a TokenConfigMutex,
+        activity_mutex: &'a ActivityMutex,
+    ) -> Self {
+        Self {
+            storage,
+            token_key,
+            token_config_mutex,
+            activity_mutex,
+            _config: PhantomData,
+        }
+    }
+
+    fn files_key(&self) -> String {
+        join_key(&self.token_key, FILES_DIRECTORY)
+    }
+
+    fn file_key(&self, filename: &Filename) -> String {
+        join_key(&self.files_key(), &path_key(filename.as_ref()))
+    }
+
+    fn thumbnails_key(&self) -> String {
+        join_key(&self.token_key, THUMBNAILS_DIRECTORY)
+    }
+
+    fn thumbnail_key(&self, filename: &Filename) -> String {
+        join_key(
+            &self.thumbnails_key(),
+            &format!(
+                "{}.{}",
+                path_key(filename.as_ref()),
+                thumbnails::THUMBNAIL_EXTENSION
+            ),
+        )
+    }
+
+    async fn create(&self, config: &C) -> Result<()> {
+        self.token_config_mutex
+            .lock()
+            .await
+            .create_token_config(self.storage, &self.token_key, config)
+            .await
+    }
+
+    async fn create_bare(&self, config: &C) -> Result<()> {
+        self.token_config_mutex
+            .lock()
+            .await
+            .create_bare_token_config(self.storage, &self.token_key, config)
+            .await
+    }
+
+    async fn load(&self) -> Result<C> {
+        self.token_config_mutex
+            .lock()
+            .await
+            .token_config(self.storage, &self.token_key)
+            .await
+    }
+
+    /// Loads the config, then calls `f` before the config may be changed by anything else
+    async fn inspect<T, F: FnOnce(&C) -> Result<T>>(&self, f: F) -> Result<T> {
+        let _core = self.token_config_mutex.lock().await;
+
+        let config = TokenConfigMutexCore::load_config(self.storage, &self.token_key)
+            .await
+            .map_err(|err| err.context(TokenNotFound))?;
+
+        f(&config)
+    }
+
+    async fn update<T, F: FnOnce(&mut C) -> Result<T>>(&self, f: F) -> Result<T> {
+        self.token_config_mutex
+            .lock()
+            .await
+            .with_token_config_mut(self.storage, &self.token_key, f)
+            .await
+    }
+
+    async fn manifest(&self) -> Result<Manifest> {
+        self.token_config_mutex
+            .lock()
+            .await
+            .manifest(self.storage, &self.token_key)
+            .await
+    }
+
+    async fn update_manifest<T, F: FnOnce(&mut Manifest) -> Result<T>>(&self, f: F) -> Result<T> {
+        self.token_config_mutex
+            .lock()
+            .await
+            .with_manifest_mut(self.storage, &self.token_key, f)
+            .await
+    }
+
+    async fn cached_usage(&self) -> (Option<TokenUsage>, u64) {
+        self.token_config_mutex
+            .lock()
+            .await
+            .cached_usage(&self.token_key)
+    }
+
+    async fn cache_usage(&self, usage: TokenUsage, file_changes: u64) {
+        self.token_config_mutex
+            .lock()
+            .await
+            .cache_usage(&self.token_key, usage, file_changes)
+    }
+
+    async fn activity(&self) -> Result<TokenActivity> {
+        let cached = self
+            .activity_mutex
+            .lock()
+            .unwrap()
+            .activity
+            .get(&self.token_key)
+            .copied();
+
+        if let Some(activity) = cached {
+            return Ok(activity);
+        }
+
+        let activity = ActivityCore::load(self.storage, &self.token_key).await?;
+
+        // Activity recorded while loading is newer
+        Ok(*self
+            .activity_mutex
+            .lock()
+            .unwrap()
+            .activity
+            .entry(self.token_key.clone())
+            .or_insert(activity))
+    }
+
+    /// Records that a user used the token. Failures are only logged, as they don't affect users
+    async fn record_access(&self, download: bool) {
+        if let Err(err) = self.try_record_access(download).await {
+            tracing::warn!("Failed to record access: {err:#}");
+        }
+    }
+
+    async fn try_record_access(&self, download: bool) -> Result<()> {
+        // Loads the activity, if it hasn't been loaded yet
+        self.activity().await?;
+
+        let now = Timestamp::now()?;
+
+        {
+            let mut core = self.activity_mutex.lock().unwrap();
+            let core = &mut *core;
+
+            let activity = core.activity.entry(self.token_key.clone()).or_default();
+
+            // Access is only shown to the minute, so needn't be saved more often than that
+            let recently_accessed = activity
+                .last_accessed
+                .is_some_and(|last_accessed| last_accessed + time::Duration::minutes(1) > now);
+
+            if recently_accessed && !download {
+                return Ok(());
+            }
+
+            activity.last_accessed = Some(now);
+
+            if download {
+                activity.downloads += 1;
+            }
+
+            core.unsaved.insert(self.token_key.clone());
+
+            // The save in progress saves this change too
+            if !core.saving.insert(self.token_key.clone()) {
+                return Ok(());
+            }
+        }
+
+        let mut save = ActivitySave {
+            activity_mutex: self.activity_mutex,
+            token_key: &self.token_key,
+            finished: false,
+        };
+
+        while let Some(activity) = save.next() {
+            ActivityCore::save(self.storage, &self.token_key, &activity).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the checksums of newly uploaded files, which replace any references of the same
+    /// name
+    async fn add_to_manifest(&self, files: &[UploadedFile]) -> Result<()> {
+        if files.is_empty() {
+            // Files which failed to upload may have been partly written
+            self.token_config_mutex
+                .lock()
+                .await
+                .files_changed(&self.token_key);
+
+            return Ok(());
+        }
+
+        self.update_manifest(|manifest| {
+            for file in files {
+                manifest.files.insert(
+                    file.name.clone(),
+                    ManifestEntry {
+                        sha256: Some(file.sha256.clone()),
+                        reference: None,
+                    },
+                );
+            }
+
+            Ok(())
+        })
+        .await
     }
 }
 
 struct Controller {
     config: AppConfig,
+    storage: Arc<dyn Storage>,
     token_config_mutex: TokenConfigMutex,
+    activity_mutex: ActivityMutex,
+    download_bucket: Option<Arc<TokenBucket>>,
+    share_download_buckets: std::sync::Mutex<HashMap<Token, Weak<TokenBucket>>>,
+    webhooks: Webhooks,
+    mailer: Option<Mailer>,
+    metrics: Arc<Metrics>,
+    master_key: Option<MasterKey>,
+    imports: Imports,
+    space_ledger: SpaceLedgerMutex,
 }
 
 impl Controller {
-    fn get_token_config<C: IsTokenConfig>(&self, token: &Token) -> TokenConfig<C> {
-        TokenConfig::new(
-            C::storage_directory(&self.config).join(token.as_str()),
-            &self.token_config_mutex,
-        )
+    /// Records uploads rejected by quotas or restrictions in the metrics
+    fn record_rejection<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(err) = &result {
+            if let Some(QuotaExceeded { rejection, .. }) = err.downcast_ref() {
+                self.metrics.quota_rejection(*rejection);
+            }
+        }
+
+        result
+    }
+
+    fn notify(&self, webhooks: &[Webhook], email_recipients: &[String], event: &Event) {
+        self.webhooks.send(webhooks, event);
+
+        if let Some(mailer) = &self.mailer {
+            mailer.send(email_recipients, event);
+        } else if !email_recipients.is_empty() {
+            tracing::warn!("Not sending email to {email_recipients:?} as no SMTP server is set");
+        }
+    }
+
+    /// The bucket shared by all concurrent downloads from a share
+    fn share_download_bucket(&self, token: &Token, rate: ByteCount) -> Arc<TokenBucket> {
+        let mut buckets = self.share_download_buckets.lock().unwrap();
+
+        buckets.retain(|_, bucket| bucket.strong_count() > 0);
+
+        if let Some(bucket) = buckets.get(token).and_then(Weak::upgrade) {
+            if bucket.rate() == rate {
+                return bucket;
+            }
+        }
+
+        let bucket = Arc::new(TokenBucket::new(rate));
+
+        buckets.insert(token.clone(), Arc::downgrade(&bucket));
+
+        bucket
     }
 
-    fn get_share_config(&self, token: &Token) -> TokenConfig<ShareConfig> {
-        self.get_token_config(token)
-    }
+    /// The size of the shares and uploads directories, which is only found by walking them
+    /// when first needed
+    async fn stored_size(&self) -> Result<StoredSize> {
+        let changes = {
+            let ledger = self.space_ledger.lock().unwrap();
+
+            if let Some(stored) = ledger.stored {
+                return Ok(stored);
+            }
+
+            ledger.changes
+        };
+
+        let stored = StoredSize {
+            shares: self.storage.usage(&self.config.shares_key()).await?,
+            uploads: self.storage.usage(&self.config.uploads_key()).await?,
+        };
+
+        let mut ledger = self.space_ledger.lock().unwrap();
+
+        if ledger.changes == changes {
+            ledger.stored = Some(stored);
+        }
+
+        Ok(stored)
+    }
+
+    async fn storage_usage(&self) -> Result<StorageUsage> {
+        let stored = self.stored_size().await?;
+
+        self.storage_usage_of(stored.shares, stored.uploads).await
+    }
+
+    async fn storage_usage_of(
+        &self,
+        shares: ByteCount,
+        uploads: ByteCount,
+    ) -> Result<StorageUsage> {
+        let mut total = shares;
+        total += uploads;
+
+        Ok(StorageUsage {
+            shares,
+            uploads,
+            total,
+            storage_quota: self.config.storage_quota.map(ByteCount),
+            available_space: self.storage.available_space().await?,
+            min_free_space: ByteCount(self.config.min_free_space),
+        })
+    }
+
+    /// Starts reserving space for files as they are written, failing if storage is full
+    async fn reserve_space(&self, area: StorageArea) -> Result<SpaceReservation<'_>> {
+        let stored = self.stored_size().await?;
+
+        let free_space = self
+            .storage
+            .available_space()
+            .await?
+            .map(|available_space| {
+                available_space.saturating_sub(ByteCount(self.config.min_free_space))
+            });
+
+        let storage_quota = self.config.storage_quota.map(ByteCount);
+
+        let reservations_at_start = self.space_ledger.lock().unwrap().reservations;
+
+        let reservation = SpaceReservation {
+            ledger: &self.space_ledger,
+            area,
+            token_key: None,
+            reserved: ByteCount(0),
+            storage_quota,
+            stored_at_start: stored,
+            free_space,
+            reservations_at_start,
+        };
+
+        if reservation.storage_space_remaining() == ByteCount(0) {
+            return Err(quota_exceeded(QuotaRejection::Space, "Storage is full"));
+        }
+
+        Ok(reservation)
+    }
+
+    fn get_token_config<C: IsTokenConfig>(&self, token: &Token) -> TokenConfig<'_, C> {
+        TokenConfig::new(
+            self.storage.as_ref(),
+            join_key(&C::storage_key(&self.config), token.as_str()),
+            &self.token_config_mutex,
+            &self.activity_mutex,
+        )
+    }
+
+    /// Records the token being accessed in the current request's span, so that log messages
+    /// can be correlated with the token
+    fn record_token(token: &Token) {
+        tracing::Span::current().record("token", token.log_id());
+    }
+
+    fn get_share_config(&self, token: &Token) -> TokenConfig<'_, ShareConfig> {
+        Self::record_token(token);
+
+        self.get_token_config(token)
+    }
+
+    fn get_upload_config(&self, token: &Token) -> TokenConfig<'_, UploadConfig> {
+        Self::record_token(token);
+
+        self.get_token_config(token)
+    }
+
+    /// Exchanges have no files or keys of their own, so aren't an `IsTokenConfig`
+    fn get_exchange_config(&self, token: &Token) -> TokenConfig<'_, ExchangeConfig> {
+        Self::record_token(token);
+
+        TokenConfig::new(
+            self.storage.as_ref(),
+            join_key(&self.config.exchanges_key(), token.as_str()),
+            &self.token_config_mutex,
+            &self.activity_mutex,
+        )
+    }
+
+    /// The tokens stored in a directory, with their usage and activity, sorted by name
+    async fn token_listings<C: ListedTokenConfig>(
+        &self,
+        directory_key: &str,
+    ) -> Result<Vec<TokenListing>> {
+        let mut token_listings = Vec::new();
+
+        for entry in self.storage.list(directory_key).await? {
+            let token = match entry {
+                Entry::Directory { name } => Token(name),
+                Entry::File { .. } => continue,
+            };
+
+            let token_config = TokenConfig::<C>::new(
+                self.storage.as_ref(),
+                join_key(directory_key, token.as_str()),
+                &self.token_config_mutex,
+                &self.activity_mutex,
+            );
+
+            let config = match token_config.load().await {
+                Ok(config) => config,
+                Err(err) => {
+                    tracing::warn!("{err:#}");
+                    continue;
+                }
+            };
+
+            let mut listing = config.listing(token);
+
+            if C::HAS_FILES {
+                match self.token_usage(&token_config).await {
+                    Ok(usage) => listing.usage = Some(usage),
+                    Err(err) => {
+                        tracing::warn!("Failed to get usage of {}: {err:#}", listing.token.log_id())
+                    }
+                }
+            }
+
+            match token_config.activity().await {
+                Ok(activity) => listing.activity = activity,
+                Err(err) => tracing::warn!(
+                    "Failed to get activity of {}: {err:#}",
+                    listing.token.log_id()
+                ),
+            }
+
+            token_listings.push(listing);
+        }
+
+        token_listings.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(token_listings)
+    }
+
+    /// Finding usage means listing the token's files, so it is cached until they change
+    async fn token_usage<C: serde::Serialize + serde::de::DeserializeOwned>(
+        &self,
+        token_config: &TokenConfig<'_, C>,
+    ) -> Result<TokenUsage> {
+        let (cached_usage, file_changes) = token_config.cached_usage().await;
+
+        if let Some(usage) = cached_usage {
+            return Ok(usage);
+        }
+
+        let mut usage = TokenUsage::default();
+
+        for entry in self.storage.list(&token_config.files_key()).await? {
+            if let Entry::File { size, .. } = entry {
+                usage.file_count += 1;
+                usage.stored_size += size;
+            }
+        }
+
+        let manifest = token_config.manifest().await?;
+
+        usage.file_count += manifest
+            .files
+            .values()
+            .filter(|manifest_entry| manifest_entry.reference.is_some())
+            .count() as u64;
+
+        token_config.cache_usage(usage, file_changes).await;
+
+        Ok(usage)
+    }
+
+    /// A new data key for a token, wrapped with the master key, if encryption is enabled
+    fn new_wrapped_key(&self) -> Result<Option<String>> {
+        self.master_key
+            .as_ref()
+            .map(|master_key| master_key.wrap(&DataKey::generate()))
+            .transpose()
+    }
+
+    fn data_key(&self, wrapped_key: Option<&str>) -> Result<Option<DataKey>> {
+        match (wrapped_key, &self.master_key) {
+            (None, _) => Ok(None),
+            (Some(wrapped_key), Some(master_key)) => master_key.unwrap(wrapped_key).map(Some),
+            (Some(_), None) => anyhow::bail!("Token is encrypted, but no master key is set"),
+        }
+    }
+
+    async fn list_files<C: serde::Serialize + serde::de::DeserializeOwned>(
+        &self,
+        token_config: &TokenConfig<'_, C>,
+        encrypted: bool,
+    ) -> Result<Vec<FileEntry>> {
+        let mut manifest = token_config.manifest().await?;
+
+        let mut files = Vec::new();
+
+        for entry in self.storage.list(&token_config.files_key()).await? {
+            if let Entry::File {
+                name,
+                mut size,
+                modified,
+            } = entry
+            {
+                if encrypted {
+                    size = encryption::plaintext_size(size)
+                        .with_context(|| format!("Failed to get size of {name}"))?;
+                }
+
+                let sha256 = manifest
+                    .files
+                    .remove(&name)
+                    .and_then(|manifest_entry| manifest_entry.sha256);
+
+                files.push(FileEntry {
+                    mime: mime_guess::from_path(&name).first_or_octet_stream(),
+                    name,
+                    size,
+                    modified,
+                    sha256,
+                });
+            }
+        }
+
+        for (name, manifest_entry) in manifest.files {
+            let Some(reference) = manifest_entry.reference else {
+                continue;
+            };
+
+            // Files which have been removed or moved out of the share roots are left out
+            match references::resolve(&self.config.share_roots, &reference).await {
+                Ok(Reference { size, modified, .. }) => files.push(FileEntry {
+                    mime: mime_guess::from_path(&name).first_or_octet_stream(),
+                    name,
+                    size,
+                    modified,
+                    sha256: manifest_entry.sha256,
+                }),
+                Err(err) => tracing::warn!("Failed to resolve reference {name}: {err:#}"),
+            }
+        }
+
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(files)
+    }
+
+    /// The size of a file's contents, given the size of the file in storage
+    fn file_size(
+        &self,
+        key: &str,
+        data_key: Option<&DataKey>,
+        stored_size: ByteCount,
+    ) -> Result<ByteCount> {
+        match data_key {
+            Some(_) => encryption::plaintext_size(stored_size)
+                .with_context(|| format!("Failed to get size of {key}")),
+            None => Ok(stored_size),
+        }
+    }
+
+    /// Opens part of a file, decrypting it if it is encrypted
+    async fn open_file(
+        &self,
+        key: &str,
+        data_key: Option<&DataKey>,
+        range: Option<(Bound<u64>, Bound<u64>)>,
+    ) -> Result<OpenedFile> {
+        let stored_size = self.storage.size(key).await?;
+
+        let size = self.file_size(key, data_key, stored_size)?;
+
+        let range = requested_range(range, size)?;
+
+        let bytes = range.clone().unwrap_or(0..size.0);
+
+        let stream = match data_key {
+            Some(data_key) => {
+                encryption::open(self.storage.as_ref(), key, data_key, stored_size, bytes).await?
+            }
+            None => self.storage.open(key, bytes).await?,
+        };
+
+        Ok(OpenedFile {
+            stream,
+            size,
+            range,
+        })
+    }
+
+    /// Opens part of a shared file, which may be a reference to a file on the server
+    async fn open_share_file(
+        &self,
+        share_config: &TokenConfig<'_, ShareConfig>,
+        data_key: Option<&DataKey>,
+        filename: &Filename,
+        range: Option<(Bound<u64>, Bound<u64>)>,
+    ) -> Result<OpenedFile> {
+        let reference = share_config
+            .manifest()
+            .await?
+            .files
+            .remove(&filename.to_string())
+            .and_then(|manifest_entry| manifest_entry.reference);
+
+        let reference = match reference {
+            Some(reference) => reference,
+            None => {
+                return self
+                    .open_file(&share_config.file_key(filename), data_key, range)
+                    .await
+            }
+        };
+
+        // References are never encrypted, as they are outside of storage
+        let Reference { path, size, .. } =
+            references::resolve(&self.config.share_roots, &reference).await?;
+
+        let range = requested_range(range, size)?;
+
+        let stream = storage::open_local(&path, range.clone().unwrap_or(0..size.0)).await?;
+
+        Ok(OpenedFile {
+            stream,
+            size,
+            range,
+        })
+    }
+
+    async fn cached_thumbnail(
+        &self,
+        share_config: &TokenConfig<'_, ShareConfig>,
+        data_key: Option<&DataKey>,
+        filename: &Filename,
+    ) -> Result<Vec<u8>> {
+        let key = share_config.thumbnail_key(filename);
+
+        let OpenedFile { stream, .. } = self.open_file(&key, data_key, None).await?;
+
+        read_stream(stream)
+            .await
+            .with_context(|| format!("Failed to read {key}"))
+    }
+
+    /// Generates a thumbnail of a shared image, and caches it alongside the share's files
+    async fn cache_thumbnail(
+        &self,
+        share_config: &TokenConfig<'_, ShareConfig>,
+        data_key: Option<&DataKey>,
+        filename: &Filename,
+    ) -> Result<Vec<u8>> {
+        let key = share_config.file_key(filename);
+
+        let OpenedFile { stream, size, .. } = self
+            .open_share_file(share_config, data_key, filename, None)
+            .await?;
+
+        if size > thumbnails::MAX_IMAGE_SIZE {
+            return Err(anyhow::Error::new(UnsupportedFile)
+                .context(format!("{key} is too large to generate a thumbnail of")));
+        }
+
+        let image = read_stream(stream)
+            .await
+            .with_context(|| format!("Failed to read {key}"))?;
+
+        let thumbnail = tokio::task::spawn_blocking(move || thumbnails::generate(&image))
+            .await
+            .context("Failed to join thumbnail task")?
+            .map_err(|err| {
+                err.context(UnsupportedFile)
+                    .context(format!("Failed to generate thumbnail of {key}"))
+            })?;
+
+        // The thumbnail can still be served if it can't be cached
+        if let Err(err) = self
+            .write_thumbnail(share_config, data_key, filename, &thumbnail)
+            .await
+        {
+            tracing::warn!("Failed to cache thumbnail of {key}: {err:#}");
+        }
+
+        tracing::debug!("Generated thumbnail of {filename}");
+
+        Ok(thumbnail)
+    }
+
+    async fn write_thumbnail(
+        &self,
+        share_config: &TokenConfig<'_, ShareConfig>,
+        data_key: Option<&DataKey>,
+        filename: &Filename,
+        thumbnail: &[u8],
+    ) -> Result<()> {
+        self.storage
+            .create_directory(&share_config.thumbnails_key())
+            .await?;
+
+        let mut writer = create_file(
+            self.storage.as_ref(),
+            &share_config.thumbnail_key(filename),
+            data_key,
+        )
+        .await?;
+
+        writer.write(thumbnail).await?;
+        writer.finish().await
+    }
+
+    /// Copies files on the server into a share, recording the progress of the import
+    async fn run_import(&self, token: &Token, id: usize, files: Vec<SourceFile>) {
+        let result = self.import_files(token, id, files).await;
+
+        if let Err(err) = &result {
+            tracing::error!("Failed to import files to {}: {err:#}", token.log_id());
+        }
+
+        self.imports.update(id, |import| {
+            import.current_file = None;
+            import.state = match result {
+                Ok(()) => ImportState::Completed,
+                Err(err) => ImportState::Failed {
+                    error: format!("{err:#}"),
+                },
+            };
+        });
+    }
+
+    async fn import_files(&self, token: &Token, id: usize, files: Vec<SourceFile>) -> Result<()> {
+        let share_config = self.get_share_config(token);
+
+        let ShareConfig { wrapped_key, .. } = share_config.load().await?;
+
+        let data_key = self.data_key(wrapped_key.as_deref())?;
+
+        let mut reservation = self.reserve_space(StorageArea::Shares).await?;
+
+        let existing_files =
+            ExistingFiles::list(self.storage.as_ref(), &share_config.files_key()).await?;
+
+        let file_count = files.len();
+
+        for (index, file) in files.into_iter().enumerate() {
+            if existing_files.contains(Path::new(&file.name)) {
+                tracing::info!(
+                    "Skipping {}, as {} already has {}",
+                    file.path.display(),
+                    token.log_id(),
+                    file.name
+                );
+
+                self.imports
+                    .update(id, |import| import.skipped_files.push(file.name.clone()));
+
+                continue;
+            }
+
+            self.imports.update(id, |import| {
+                import.current_file = Some(file.name.clone());
+            });
+
+            let imported_file = self
+                .import_file(
+                    &share_config,
+                    data_key.as_ref(),
+                    &mut reservation,
+                    id,
+                    &file,
+                )
+                .await?;
+
+            reservation.store(imported_file.size);
+
+            share_config
+                .add_to_manifest(std::slice::from_ref(&imported_file))
+                .await?;
+
+            self.imports.update(id, |import| import.imported_files += 1);
+
+            tracing::info!(
+                "Imported {} to {} ({} of {file_count})",
+                file.path.display(),
+                token.log_id(),
+                index + 1
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn import_file(
+        &self,
+        share_config: &TokenConfig<'_, ShareConfig>,
+        data_key: Option<&DataKey>,
+        reservation: &mut SpaceReservation<'_>,
+        id: usize,
+        file: &SourceFile,
+    ) -> Result<UploadedFile> {
+        let filename = Path::new(&file.name);
+
+        let mut new_file = NewFile::new(
+            self.storage.as_ref(),
+            &join_key(&share_config.files_key(), &path_key(filename)),
+            filename,
+            None,
+            data_key,
+        )
+        .await?;
+
+        let mut source = tokio::fs::File::open(&file.path)
+            .await
+            .with_context(|| format!("Failed to open {}", file.path.display()))?;
+
+        let mut buffer = vec![0; IMPORT_CHUNK_SIZE];
+
+        loop {
+            let length = source
+                .read(&mut buffer)
+                .await
+                .with_context(|| format!("Failed to read {}", file.path.display()))?;
+
+            if length == 0 {
+                break;
+            }
+
+            new_file
+                .write_all(&buffer[..length], Some(&mut *reservation))
+                .await?;
+
+            self.imports.update(id, |import| {
+                import.imported_size += ByteCount(length as u64);
+            });
+        }
+
+        let (size, sha256) = new_file.close().await?;
+
+        Ok(UploadedFile {
+            name: file.name.clone(),
+            size,
+            sha256,
+        })
+    }
+
+    /// Re-wraps the data keys of all tokens of one kind with a new master key. Tokens whose
+    /// keys are already wrapped with the new master key are skipped, so that an interrupted
+    /// rotation can be resumed
+    async fn rotate_token_keys<C: IsTokenConfig>(&self, new_master_key: &MasterKey) -> Result<u64> {
+        let master_key = self
+            .master_key
+            .as_ref()
+            .context("No master key is set, so there are no keys to rotate")?;
+
+        let mut rotated = 0;
+
+        for entry in self.storage.list(&C::storage_key(&self.config)).await? {
+            let token = match entry {
+                Entry::Directory { name } => Token(name),
+                Entry::File { .. } => continue,
+            };
+
+            let was_rotated = self
+                .get_token_config::<C>(&token)
+                .update(|config| {
+                    let wrapped_key = match config.wrapped_key_mut() {
+                        Some(wrapped_key) => wrapped_key,
+                        None => return Ok(false),
+                    };
+
+                    let data_key = match master_key.unwrap(wrapped_key) {
+                        Ok(data_key) => data_key,
+                        Err(_) if new_master_key.unwrap(wrapped_key).is_ok() => return Ok(false),
+                        Err(err) => return Err(err),
+                    };
+
+                    *wrapped_key = new_master_key.wrap(&data_key)?;
+
+                    Ok(true)
+                })
+                .await
+                .with_context(|| format!("Failed to rotate key of {}", token.log_id()))?;
+
+            if was_rotated {
+                tracing::info!("Rotated key of {}", token.log_id());
+
+                rotated += 1;
+            }
+        }
+
+        Ok(rotated)
+    }
+}
+
+pub struct Filename(std::path::PathBuf);
+
+impl Filename {
+    pub fn display(&self) -> std::path::Display<'_> {
+        self.0.display()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Filename {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let path = String::deserialize(deserializer)?;
+        let path = std::path::Path::new(&path);
+
+        let mut components = path.components();
+
+        let filename = components
+            .next()
+            .ok_or_else(|| serde::de::Error::custom("Bad Path"))?;
+
+        let filename = if let std::path::Component::Normal(filename) = filename {
+            filename
+        } else {
+            return Err(serde::de::Error::custom("Bad Path"));
+        };
+
+        if components.next().is_some() {
+            return Err(serde::de::Error::custom("Bad Path"));
+        }
+
+        Ok(Self(std::path::PathBuf::from(filename)))
+    }
+}
+
+impl AsRef<Path> for Filename {
+    fn as_ref(&self) -> &Path {
+        self.0.as_path()
+    }
+}
+
+impl fmt::Display for Filename {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display().fmt(f)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenStatus {
+    Active,
+    Expired,
+}
+
+impl std::str::FromStr for TokenStatus {
+    type Err = &'static str;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "active" => Ok(Self::Active),
+            "expired" => Ok(Self::Expired),
+            _ => Err("Unknown status"),
+        }
+    }
+}
+
+/// A share, upload or exchange, as listed in the admin app
+pub struct TokenListing {
+    pub name: String,
+    pub token: Token,
+    pub expiry: Timestamp,
+    /// `None` for tokens which don't start with their creation time
+    pub created: Option<Timestamp>,
+    /// The space left of an upload token's quota
+    pub quota_remaining: Option<ByteCount>,
+    pub details: TokenDetails,
+    /// `None` for tokens which have no files of their own
+    pub usage: Option<TokenUsage>,
+    pub activity: TokenActivity,
+}
+
+impl TokenListing {
+    fn new(token: Token, name: String, expiry: Timestamp, details: TokenDetails) -> Self {
+        Self {
+            name,
+            created: token.created(),
+            token,
+            expiry,
+            quota_remaining: None,
+            details,
+            usage: None,
+            activity: TokenActivity::default(),
+        }
+    }
+
+    pub fn status(&self, now: Timestamp) -> TokenStatus {
+        if now > self.expiry {
+            TokenStatus::Expired
+        } else {
+            TokenStatus::Active
+        }
+    }
+}
+
+/// Which tokens to list. Tokens must match all of the given criteria
+#[derive(Default)]
+pub struct TokenFilter {
+    /// Found in the name, notes or tags, ignoring case
+    pub search: Option<String>,
+    pub tag: Option<String>,
+    pub status: Option<TokenStatus>,
+    /// The earliest creation date, inclusive
+    pub created_from: Option<WebDate>,
+    /// The latest creation date, inclusive
+    pub created_to: Option<WebDate>,
+}
+
+impl TokenFilter {
+    pub fn matches(&self, listing: &TokenListing, now: Timestamp) -> bool {
+        if let Some(search) = &self.search {
+            let search = search.to_lowercase();
+
+            let found = listing.name.to_lowercase().contains(&search)
+                || listing.details.notes.to_lowercase().contains(&search)
+                || listing.details.tags.iter().any(|tag| tag.contains(&search))
+                || listing.token.log_id() == search;
+
+            if !found {
+                return false;
+            }
+        }
+
+        if let Some(tag) = &self.tag {
+            if !listing.details.tags.contains(tag) {
+                return false;
+            }
+        }
+
+        if let Some(status) = self.status {
+            if listing.status(now) != status {
+                return false;
+            }
+        }
+
+        if self.created_from.is_some() || self.created_to.is_some() {
+            let Some(created) = listing.created.map(Timestamp::date) else {
+                return false;
+            };
+
+            if self.created_from.is_some_and(|from| created < from)
+                || self.created_to.is_some_and(|to| created > to)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The settings of a new exchange, which are split between its share and upload token
+pub struct NewExchange {
+    pub name: String,
+    pub expiry: Timestamp,
+    pub space_quota: ByteCount,
+    pub restrictions: UploadRestrictions,
+    pub email_recipients: Vec<String>,
+    pub webhooks: Vec<Webhook>,
+    /// The tags are given to the share and upload token too, so that they're found by them
+    pub details: TokenDetails,
+}
+
+/// What the recipient of an exchange sees: the files provided to them, and what they may upload
+pub struct Exchange {
+    pub name: String,
+    pub expiry: Timestamp,
+    pub share: Token,
+    pub upload: Token,
+    pub files: Vec<FileEntry>,
+    pub rules: UploadRules,
+}
+
+/// A requested range which lies outside of the file
+#[derive(Debug)]
+pub struct RangeNotSatisfiable {
+    pub size: ByteCount,
+}
+
+impl fmt::Display for RangeNotSatisfiable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Range not satisfiable for file of {} bytes", self.size)
+    }
+}
+
+impl std::error::Error for RangeNotSatisfiable {}
+
+/// Converts the bounds of an HTTP byte range into the range of bytes to send
+fn resolve_range(
+    (start, end): (Bound<u64>, Bound<u64>),
+    ByteCount(size): ByteCount,
+) -> Option<Range<u64>> {
+    let range = match (start, end) {
+        // The last `length` bytes of the file
+        (Bound::Unbounded, Bound::Included(length)) => size.saturating_sub(length)..size,
+        (Bound::Included(start), Bound::Included(end)) => start..end.saturating_add(1).min(size),
+        (Bound::Included(start), Bound::Unbounded) => start..size,
+        _ => return None,
+    };
+
+    (!range.is_empty()).then_some(range)
+}
+
+/// The part of a file of `size` bytes to send, if only part of it was requested
+fn requested_range(
+    range: Option<(Bound<u64>, Bound<u64>)>,
+    size: ByteCount,
+) -> Result<Option<Range<u64>>> {
+    match range {
+        Some(range) => Ok(Some(
+            resolve_range(range, size).ok_or(RangeNotSatisfiable { size })?,
+        )),
+        None => Ok(None),
+    }
+}
+
+pub struct OpenedFile {
+    pub stream: ByteStream,
+    /// The size of the whole file
+    pub size: ByteCount,
+    /// The part of the file being sent, if only part of it was requested
+    pub range: Option<Range<u64>>,
+}
+
+pub struct SharedFile {
+    pub stream: ByteStream,
+    /// The size of the whole file
+    pub size: ByteCount,
+    /// The part of the file being sent, if only part of it was requested
+    pub range: Option<Range<u64>>,
+    pub mime: mime_guess::Mime,
+    pub throttle: Throttle,
+    /// `None` if the part of the file being sent doesn't reach the end of the file
+    pub completion: Option<DownloadCompletion>,
+    pub transfer: Transfer,
+}
+
+/// Sends notifications once a shared file has been completely downloaded
+pub struct DownloadCompletion {
+    controller: Arc<Controller>,
+    token: Token,
+    webhooks: Vec<Webhook>,
+    email_recipients: Vec<String>,
+    event: Event,
+}
+
+impl DownloadCompletion {
+    pub fn complete(self) {
+        self.controller
+            .notify(&self.webhooks, &self.email_recipients, &self.event);
+
+        tokio::spawn(async move {
+            self.controller
+                .get_share_config(&self.token)
+                .record_access(true)
+                .await
+        });
+    }
+}
+
+pub struct StorageUsage {
+    pub shares: ByteCount,
+    pub uploads: ByteCount,
+    pub total: ByteCount,
+    pub storage_quota: Option<ByteCount>,
+    /// `None` if the storage is unlimited, e.g. an object store
+    pub available_space: Option<ByteCount>,
+    pub min_free_space: ByteCount,
+}
+
+pub struct UploadRules {
+    pub space_quota: ByteCount,
+    pub restrictions: UploadRestrictions,
+    pub remaining_file_count: Option<u64>,
+}
+
+pub struct FileEntry {
+    pub name: String,
+    pub size: ByteCount,
+    pub modified: Option<Timestamp>,
+    pub mime: mime_guess::Mime,
+    /// `None` for files uploaded before checksums were recorded
+    pub sha256: Option<String>,
+}
+
+impl FileEntry {
+    /// An emoji representing the kind of file
+    pub fn icon(&self) -> &'static str {
+        match (self.mime.type_().as_str(), self.mime.subtype().as_str()) {
+            ("image", _) => "🖼️",
+            ("audio", _) => "🎵",
+            ("video", _) => "🎬",
+            ("text", _) => "📝",
+            ("application", "pdf") => "📕",
+            (
+                "application",
+                "zip" | "gzip" | "x-tar" | "x-7z-compressed" | "x-bzip2" | "x-xz" | "vnd.rar",
+            ) => "📦",
+            _ => "📄",
+        }
+    }
+
+    /// The start of the checksum, which is enough to tell files apart at a glance
+    pub fn short_sha256(&self) -> Option<&str> {
+        self.sha256
+            .as_deref()
+            .map(|sha256| &sha256[..sha256.len().min(12)])
+    }
+
+    pub fn has_thumbnail(&self) -> bool {
+        thumbnails::is_supported(Path::new(&self.name))
+    }
+
+    pub fn has_preview(&self) -> bool {
+        preview::kind(Path::new(&self.name)).is_some()
+    }
+}
+
+/// A file on the server which is shared by reference
+pub struct SharedReference {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+pub struct FilePreview {
+    pub token: Token,
+    pub filename: Filename,
+    pub size: ByteCount,
+    pub mime: mime_guess::Mime,
+    pub kind: PreviewKind,
+    /// The start of the file, if it is text
+    pub text: Option<String>,
+    /// Whether `text` is only part of the file
+    pub truncated: bool,
+    /// Whether the share only has this file, so has no listing to go back to
+    pub single_file: bool,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Date,
+}
+
+impl SortKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Date => "date",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(askama::Template)]
+#[template(path = "user_share_directory_listing.html")]
+pub struct ShareDirectoryListing {
+    pub token: Token,
+    pub name: String,
+    /// The names and sizes of the files are of their encrypted form
+    pub end_to_end: bool,
+    /// Show the files as a grid of thumbnails rather than a table
+    gallery: bool,
+    sort: SortKey,
+    order: SortOrder,
+    pub files: Vec<FileEntry>,
+}
+
+impl ShareDirectoryListing {
+    pub fn total_size(&self) -> ByteCount {
+        let mut total_size = ByteCount(0);
+
+        for file in &self.files {
+            total_size += file.size;
+        }
+
+        total_size
+    }
+
+    /// The query of a link to this listing, with the given view and the current sorting
+    fn view_query(&self, gallery: bool) -> String {
+        format!(
+            "?view={}&sort={}&order={}",
+            if gallery { "gallery" } else { "table" },
+            self.sort.as_str(),
+            match self.order {
+                SortOrder::Asc => "asc",
+                SortOrder::Desc => "desc",
+            }
+        )
+    }
+
+    /// The query of a link which sorts by `sort`, reversing the order if already sorted by it
+    fn sort_query(&self, sort: SortKey) -> String {
+        let descending = sort == self.sort && self.order == SortOrder::Asc;
+
+        format!(
+            "?view={}&sort={}&order={}",
+            if self.gallery { "gallery" } else { "table" },
+            sort.as_str(),
+            if descending { "desc" } else { "asc" }
+        )
+    }
+
+    fn sort_indicator(&self, sort: SortKey) -> &'static str {
+        match (sort == self.sort, self.order) {
+            (false, _) => "",
+            (true, SortOrder::Asc) => " ▲",
+            (true, SortOrder::Desc) => " ▼",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Admin {
+    controller: Arc<Controller>,
+}
+
+impl Admin {
+    pub fn config(&self) -> &AppConfig {
+        &self.controller.config
+    }
+
+    pub async fn current_shares(&self) -> Result<Vec<TokenListing>> {
+        self.controller
+            .token_listings::<ShareConfig>(&self.config().shares_key())
+            .await
+    }
+
+    pub async fn new_share_token(&self, config: ShareConfig) -> Result<Token> {
+        let token = Token::new()?;
+
+        let config = ShareConfig {
+            wrapped_key: self.controller.new_wrapped_key()?,
+            ..config
+        };
+
+        self.controller
+            .get_share_config(&token)
+            .create(&config)
+            .await?;
+
+        Ok(token)
+    }
+
+    pub async fn current_share_config(&self, token: &Token) -> Result<ShareConfig> {
+        self.controller.get_share_config(token).load().await
+    }
+
+    pub async fn set_share_details(&self, token: &Token, details: TokenDetails) -> Result<()> {
+        self.controller
+            .get_share_config(token)
+            .set_details(details)
+            .await
+    }
+
+    pub async fn shared_files(&self, token: &Token) -> Result<Vec<FileEntry>> {
+        let share_config = self.controller.get_share_config(token);
+
+        let encrypted = share_config.load().await?.wrapped_key.is_some();
+
+        self.controller.list_files(&share_config, encrypted).await
+    }
+
+    pub async fn shared_references(&self, token: &Token) -> Result<Vec<SharedReference>> {
+        Ok(self
+            .controller
+            .get_share_config(token)
+            .manifest()
+            .await?
+            .files
+            .into_iter()
+            .filter_map(|(name, manifest_entry)| {
+                manifest_entry
+                    .reference
+                    .map(|path| SharedReference { name, path })
+            })
+            .collect())
+    }
+
+    /// Adds a file on the server to a share without copying it. The path must be within one
+    /// of the share roots, both now and whenever the file is downloaded
+    pub async fn share_reference(&self, token: &Token, path: &Path) -> Result<()> {
+        let share_config = self.controller.get_share_config(token);
+
+        let ShareConfig { end_to_end, .. } = share_config.load().await?;
+
+        if end_to_end {
+            anyhow::bail!("Files on the server can't be added to end-to-end encrypted shares");
+        }
+
+        // Symlinks are shared under their own name, rather than that of their target
+        let name = path
+            .file_name()
+            .with_context(|| format!("{} has no filename", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+
+        let Reference { path, .. } = references::resolve(&self.config().share_roots, path).await?;
+
+        let exists = self
+            .controller
+            .storage
+            .list(&share_config.files_key())
+            .await?
+            .iter()
+            .any(|entry| matches!(entry, Entry::File { name: existing, .. } if *existing == name));
+
+        if exists {
+            anyhow::bail!("The share already has a file called {name}");
+        }
+
+        share_config
+            .update_manifest(|manifest| {
+                if manifest.files.contains_key(&name) {
+                    anyhow::bail!("The share already has a file called {name}");
+                }
+
+                tracing::info!("Sharing {} as {name}", path.display());
+
+                manifest.files.insert(
+                    name,
+                    ManifestEntry {
+                        sha256: None,
+                        reference: Some(path),
+                    },
+                );
+
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn remove_reference(&self, token: &Token, name: &str) -> Result<()> {
+        self.controller
+            .get_share_config(token)
+            .update_manifest(|manifest| {
+                match manifest.files.get(name) {
+                    Some(ManifestEntry {
+                        reference: Some(_), ..
+                    }) => manifest.files.remove(name),
+                    _ => anyhow::bail!("{name} is not shared by reference"),
+                };
+
+                Ok(())
+            })
+            .await
+    }
+
+    /// Makes the share's link lead to just one of its files, or to all of them if `None`
+    pub async fn set_single_file(&self, token: &Token, filename: Option<String>) -> Result<()> {
+        let share_config = self.controller.get_share_config(token);
+
+        let ShareConfig {
+            end_to_end,
+            wrapped_key,
+            ..
+        } = share_config.load().await?;
+
+        if let Some(filename) = &filename {
+            // The names of end-to-end encrypted files are unknown to the server, and their
+            // links need the listing to decrypt them
+            if end_to_end {
+                anyhow::bail!("End-to-end encrypted shares can't be single file shares");
+            }
+
+            let files = self
+                .controller
+                .list_files(&share_config, wrapped_key.is_some())
+                .await?;
+
+            if !files.iter().any(|file| file.name == *filename) {
+                anyhow::bail!("The share has no file called {filename}");
+            }
+        }
+
+        share_config
+            .update(|config| {
+                config.single_file = filename;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Checks that files can be imported into a share, and records the start of the import
+    async fn prepare_import(
+        &self,
+        token: &Token,
+        source: &Path,
+        files: &[SourceFile],
+    ) -> Result<usize> {
+        let ShareConfig {
+            expiry, end_to_end, ..
+        } = self.controller.get_share_config(token).load().await?;
+
+        if Timestamp::now()? > expiry {
+            anyhow::bail!("Token has expired");
+        }
+
+        if end_to_end {
+            anyhow::bail!("Files can't be imported into end-to-end encrypted shares");
+        }
+
+        let mut total_size = ByteCount(0);
+
+        for file in files {
+            total_size += file.size;
+        }
+
+        let reservation = self.controller.reserve_space(StorageArea::Shares).await?;
+
+        // Space is reserved as the files are copied, so this only rejects imports early
+        if total_size > reservation.storage_space_remaining() {
+            anyhow::bail!("Not enough storage space to import {}", source.display());
+        }
+
+        Ok(self.controller.imports.start(token.clone(), source, files))
+    }
+
+    /// Starts copying a file, or the files in a directory, on the server into a share in the
+    /// background, returning the ID of the import. The path must be within one of the share roots
+    pub async fn start_import(&self, token: Token, source: &Path) -> Result<usize> {
+        let files = import::source_files(source, Some(&self.config().share_roots)).await?;
+
+        let id = self.prepare_import(&token, source, &files).await?;
+
+        let controller = self.controller.clone();
+
+        tokio::spawn(
+            async move { controller.run_import(&token, id, files).await }
+                .instrument(tracing::Span::current()),
+        );
+
+        Ok(id)
+    }
+
+    /// Copies a file, or the files in a directory, on the server into a share. This is used
+    /// from the command line, so the path needn't be within the share roots
+    pub async fn import(&self, token: Token, source: &Path) -> Result<ImportProgress> {
+        let files = import::source_files(source, None).await?;
+
+        let id = self.prepare_import(&token, source, &files).await?;
+
+        self.controller.run_import(&token, id, files).await;
+
+        self.controller
+            .imports
+            .get(id)
+            .context("Import progress is missing")
+    }
+
+    pub fn imports(&self, token: &Token) -> Vec<ImportProgress> {
+        self.controller.imports.of_token(token)
+    }
+
+    /// Storage usage from the cached usage of listed tokens, which avoids walking all of their
+    /// directories. Unlike the metrics, thumbnails and configs aren't counted
+    pub async fn listed_storage_usage(
+        &self,
+        shares: &[TokenListing],
+        uploads: &[TokenListing],
+    ) -> Result<StorageUsage> {
+        fn stored_size(listings: &[TokenListing]) -> ByteCount {
+            let mut stored_size = ByteCount(0);
+
+            for usage in listings.iter().filter_map(|listing| listing.usage) {
+                stored_size += usage.stored_size;
+            }
+
+            stored_size
+        }
+
+        self.controller
+            .storage_usage_of(stored_size(shares), stored_size(uploads))
+            .await
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.controller.storage
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.controller.metrics
+    }
+
+    pub async fn render_metrics(&self) -> Result<String> {
+        self.controller
+            .metrics
+            .render(&self.controller.storage_usage().await?)
+    }
+
+    pub async fn share_files(&self, token: Token, files: Multipart) -> Result<()> {
+        let token_config = self.controller.get_share_config(&token);
+
+        let ShareConfig {
+            expiry,
+            wrapped_key,
+            end_to_end,
+            ..
+        } = token_config.load().await?;
+
+        if Timestamp::now()? > expiry {
+            anyhow::bail!("Token has expired");
+        }
+
+        let data_key = self.controller.data_key(wrapped_key.as_deref())?;
+
+        let _transfer = self.controller.metrics.upload();
+
+        let mut reservation = self
+            .controller
+            .record_rejection(self.controller.reserve_space(StorageArea::Shares).await)?;
+
+        let mut uploaded_files = Vec::new();
+
+        let write_result = NewFile::from_multipart(
+            self.controller.storage.as_ref(),
+            &token_config.files_key(),
+            files,
+            &mut uploaded_files,
+            &mut reservation,
+            &UploadRestrictions::default(),
+            data_key.as_ref(),
+        )
+        .await;
+
+        reservation.store(total_size(&uploaded_files));
+        drop(reservation);
+
+        self.controller
+            .metrics
+            .uploaded(total_size(&uploaded_files));
+
+        token_config.add_to_manifest(&uploaded_files).await?;
+
+        // The server can't read the images of end-to-end encrypted shares
+        let images = uploaded_files
+            .iter()
+            .map(|file| Filename(PathBuf::from(&file.name)))
+            .filter(|filename| !end_to_end && thumbnails::is_supported(filename.as_ref()))
+            .collect::<Vec<_>>();
+
+        if !images.is_empty() {
+            let controller = self.controller.clone();
+
+            // Thumbnails are generated in the background, as they are also generated on demand
+            tokio::spawn(
+                async move {
+                    let share_config = controller.get_token_config::<ShareConfig>(&token);
+
+                    for filename in images {
+                        if let Err(err) = controller
+                            .cache_thumbnail(&share_config, data_key.as_ref(), &filename)
+                            .await
+                        {
+                            tracing::warn!("{err:#}");
+                        }
+                    }
+                }
+                .instrument(tracing::Span::current()),
+            );
+        }
+
+        self.controller.record_rejection(write_result)
+    }
+
+    pub async fn current_uploads(&self) -> Result<Vec<TokenListing>> {
+        self.controller
+            .token_listings::<UploadConfig>(&self.config().uploads_key())
+            .await
+    }
+
+    pub async fn new_upload_token(&self, config: UploadConfig) -> Result<Token> {
+        let token = Token::new()?;
+
+        let name = if config.name.is_empty() {
+            token.0.clone()
+        } else {
+            config.name
+        };
+
+        let config = UploadConfig {
+            name,
+            wrapped_key: self.controller.new_wrapped_key()?,
+            ..config
+        };
+
+        self.controller
+            .get_upload_config(&token)
+            .create(&config)
+            .await?;
+
+        Ok(token)
+    }
+
+    pub async fn current_upload_config(&self, token: &Token) -> Result<UploadConfig> {
+        self.controller.get_upload_config(token).load().await
+    }
+
+    pub async fn set_upload_details(&self, token: &Token, details: TokenDetails) -> Result<()> {
+        self.controller
+            .get_upload_config(token)
+            .set_details(details)
+            .await
+    }
+
+    pub async fn uploaded_files(&self, token: &Token) -> Result<Vec<FileEntry>> {
+        let upload_config = self.controller.get_upload_config(token);
+
+        let encrypted = upload_config.load().await?.wrapped_key.is_some();
+
+        self.controller.list_files(&upload_config, encrypted).await
+    }
+
+    pub async fn open_uploaded_file(
+        &self,
+        token: &Token,
+        filename: &Filename,
+    ) -> Result<OpenedFile> {
+        let upload_config = self.controller.get_upload_config(token);
+
+        let UploadConfig { wrapped_key, .. } = upload_config.load().await?;
+
+        let data_key = self.controller.data_key(wrapped_key.as_deref())?;
+
+        self.controller
+            .open_file(&upload_config.file_key(filename), data_key.as_ref(), None)
+            .await
+    }
+
+    pub async fn current_exchanges(&self) -> Result<Vec<TokenListing>> {
+        self.controller
+            .token_listings::<ExchangeConfig>(&self.config().exchanges_key())
+            .await
+    }
+
+    /// Creates an exchange, along with the share and upload token which it offers
+    pub async fn new_exchange_token(
+        &self,
+        NewExchange {
+            name,
+            expiry,
+            space_quota,
+            restrictions,
+            email_recipients,
+            webhooks,
+            details,
+        }: NewExchange,
+    ) -> Result<Token> {
+        let token = Token::new()?;
+
+        let name = if name.is_empty() {
+            token.0.clone()
+        } else {
+            name
+        };
+
+        let share = self
+            .new_share_token(ShareConfig {
+                name: format!("{name} (provided)"),
+                expiry,
+                download_rate_limit: None,
+                wrapped_key: None,
+                end_to_end: false,
+                details: TokenDetails {
+                    notes: String::new(),
+                    tags: details.tags.clone(),
+                },
+                email_recipients: email_recipients.clone(),
+                webhooks: webhooks.clone(),
+                single_file: None,
+            })
+            .await?;
+
+        let upload = self
+            .new_upload_token(UploadConfig {
+                name: format!("{name} (replies)"),
+                expiry,
+                space_quota,
+                details: TokenDetails {
+                    notes: String::new(),
+                    tags: details.tags.clone(),
+                },
+                email_recipients,
+                wrapped_key: None,
+                restrictions,
+                webhooks,
+            })
+            .await?;
+
+        self.controller
+            .get_exchange_config(&token)
+            .create_bare(&ExchangeConfig {
+                name,
+                expiry,
+                share,
+                upload,
+                details,
+            })
+            .await?;
+
+        Ok(token)
+    }
+
+    pub async fn current_exchange_config(&self, token: &Token) -> Result<ExchangeConfig> {
+        self.controller.get_exchange_config(token).load().await
+    }
+
+    pub async fn set_exchange_details(&self, token: &Token, details: TokenDetails) -> Result<()> {
+        self.controller
+            .get_exchange_config(token)
+            .set_details(details)
+            .await
+    }
+
+    /// Re-wraps the data keys of all shares and uploads with a new master key,
+    /// returning how many were re-wrapped
+    pub async fn rotate_master_key(&self, new_master_key: &MasterKey) -> Result<u64> {
+        let shares = self
+            .controller
+            .rotate_token_keys::<ShareConfig>(new_master_key)
+            .await?;
+
+        let uploads = self
+            .controller
+            .rotate_token_keys::<UploadConfig>(new_master_key)
+            .await?;
+
+        Ok(shares + uploads)
+    }
+}
+
+#[derive(Clone)]
+pub struct User {
+    controller: Arc<Controller>,
+}
+
+impl User {
+    pub fn config(&self) -> &AppConfig {
+        &self.controller.config
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.controller.metrics
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.controller.storage
+    }
+
+    pub async fn upload_files(
+        &self,
+        token: Token,
+        content_length: Option<u64>,
+        files: Multipart,
+    ) -> Result<()> {
+        let _transfer = self.controller.metrics.upload();
+
+        let result = self.receive_files(token, content_length, files).await;
+
+        self.controller.record_rejection(result)
+    }
+
+    async fn receive_files(
+        &self,
+        token: Token,
+        content_length: Option<u64>,
+        files: Multipart,
+    ) -> Result<()> {
+        let token_config = self.controller.get_upload_config(&token);
+
+        let reservation = self.controller.reserve_space(StorageArea::Uploads).await?;
+
+        let token_key = token_config.token_key.clone();
+
+        // Space is reserved as files are written, so that concurrent uploads to the token can
+        // share its quota
+        let (mut reservation, upload_config, data_key) = token_config
+            .inspect(|token_config| {
+                if Timestamp::now()? > token_config.expiry {
+                    return Err(anyhow::Error::new(TokenNotFound).context("Token has expired"));
+                }
+
+                let data_key = self
+                    .controller
+                    .data_key(token_config.wrapped_key.as_deref())?;
+
+                let reservation = reservation.with_token(&token_key, token_config.space_quota);
+
+                Ok((reservation, token_config.clone(), data_key))
+            })
+            .await?;
+
+        // Content-Length is only a hint, used to reject oversized requests early. The limits
+        // are enforced on the bytes actually written.
+        if let Some(content_length) = content_length {
+            let request_size = ByteCount(content_length);
+
+            if reservation
+                .token_space_remaining()
+                .is_some_and(|remaining| request_size > remaining)
+            {
+                return Err(quota_exceeded(QuotaRejection::Space, "Out of Space"));
+            }
+
+            if request_size > reservation.storage_space_remaining() {
+                return Err(quota_exceeded(QuotaRejection::Space, "Storage is full"));
+            }
+        }
+
+        let mut uploaded_files = Vec::new();
+
+        let write_result = NewFile::from_multipart(
+            self.controller.storage.as_ref(),
+            &token_config.files_key(),
+            files,
+            &mut uploaded_files,
+            &mut reservation,
+            &upload_config.restrictions,
+            data_key.as_ref(),
+        )
+        .await;
+
+        let stored_size = total_size(&uploaded_files);
+
+        self.controller.metrics.uploaded(stored_size);
+
+        token_config
+            .update(|token_config| {
+                token_config.space_quota = token_config.space_quota.saturating_sub(stored_size);
+                reservation.store(stored_size);
+                Ok(())
+            })
+            .await?;
+
+        token_config.add_to_manifest(&uploaded_files).await?;
+
+        token_config.record_access(false).await;
+
+        // Files stored before any failure are kept, so are notified of
+        if !uploaded_files.is_empty() {
+            self.controller.notify(
+                &upload_config.webhooks,
+                &upload_config.email_recipients,
+                &Event::UploadCompleted {
+                    token,
+                    name: upload_config.name,
+                    files: uploaded_files,
+                },
+            );
+        }
+
+        write_result
+    }
+
+    pub async fn upload_rules(&self, token: &Token) -> Result<UploadRules> {
+        let token_config = self.controller.get_upload_config(token);
+
+        let UploadConfig {
+            expiry,
+            space_quota,
+            restrictions,
+            ..
+        } = token_config.load().await?;
+
+        if Timestamp::now()? > expiry {
+            anyhow::bail!("Token has expired");
+        }
+
+        token_config.record_access(false).await;
+
+        let remaining_file_count = match restrictions.max_file_count {
+            Some(max_file_count) => {
+                let file_count =
+                    count_files(self.controller.storage.as_ref(), &token_config.files_key())
+                        .await?;
+
+                Some(max_file_count.saturating_sub(file_count))
+            }
+            None => None,
+        };
+
+        Ok(UploadRules {
+            space_quota,
+            restrictions,
+            remaining_file_count,
+        })
+    }
+
+    pub async fn exchange(&self, token: &Token) -> Result<Exchange> {
+        let exchange_config = self.controller.get_exchange_config(token);
+
+        let ExchangeConfig {
+            name,
+            expiry,
+            share,
+            upload,
+            ..
+        } = exchange_config.load().await?;
+
+        if Timestamp::now()? > expiry {
+            anyhow::bail!("Token has expired");
+        }
+
+        exchange_config.record_access(false).await;
+
+        let share_config = self.controller.get_share_config(&share);
+
+        let ShareConfig {
+            wrapped_key,
+            single_file,
+            ..
+        } = share_config.load().await?;
+
+        let mut files = self
+            .controller
+            .list_files(&share_config, wrapped_key.is_some())
+            .await?;
+
+        if let Some(single_file) = single_file {
+            files.retain(|file| file.name == single_file);
+        }
+
+        share_config.record_access(false).await;
+
+        let rules = self.upload_rules(&upload).await?;
+
+        Ok(Exchange {
+            name,
+            expiry,
+            share,
+            upload,
+            files,
+            rules,
+        })
+    }
+
+    pub async fn directory_listing(
+        &self,
+        token: Token,
+        gallery: bool,
+        sort: SortKey,
+        order: SortOrder,
+    ) -> Result<ShareDirectoryListing> {
+        let share_config = self.controller.get_share_config(&token);
+
+        let ShareConfig {
+            name,
+            wrapped_key,
+            end_to_end,
+            single_file,
+            ..
+        } = share_config.load().await?;
+
+        if single_file.is_some() {
+            anyhow::bail!("Single file shares have no listing");
+        }
+
+        share_config.record_access(false).await;
+
+        let mut files = self
+            .controller
+            .list_files(&share_config, wrapped_key.is_some())
+            .await?;
+
+        // Files are already sorted by name, which breaks ties between the other keys
+        match sort {
+            SortKey::Name => (),
+            SortKey::Size => files.sort_by_key(|file| file.size),
+            SortKey::Date => files.sort_by_key(|file| file.modified),
+        }
+
+        if order == SortOrder::Desc {
+            files.reverse();
+        }
+
+        Ok(ShareDirectoryListing {
+            token,
+            name,
+            end_to_end,
+            gallery: gallery && !end_to_end,
+            sort,
+            order,
+            files,
+        })
+    }
+
+    /// The file which a single file share's link leads to, if it is one
+    pub async fn single_file(&self, token: &Token) -> Result<Option<String>> {
+        Ok(self
+            .controller
+            .get_share_config(token)
+            .load()
+            .await?
+            .single_file)
+    }
+
+    /// Previews a shared file, with the same errors as [`User::thumbnail`]
+    pub async fn preview(&self, token: Token, filename: Filename) -> Result<FilePreview> {
+        let share_config = self.controller.get_share_config(&token);
+
+        let config = share_config
+            .load()
+            .await
+            .map_err(|err| err.context(TokenNotFound))?;
+
+        if !config.is_visible(&filename) {
+            return Err(anyhow::Error::new(ObjectNotFound)
+                .context(format!("{filename} is not the file of a single file share")));
+        }
+
+        let ShareConfig {
+            wrapped_key,
+            end_to_end,
+            single_file,
+            ..
+        } = config;
+
+        if end_to_end {
+            return Err(anyhow::Error::new(UnsupportedFile)
+                .context("End-to-end encrypted files can't be previewed"));
+        }
 
-    fn get_upload_config(&self, token: &Token) -> TokenConfig<UploadConfig> {
-        self.get_token_config(token)
-    }
-}
+        share_config.record_access(false).await;
 
-pub struct Filename(std::path::PathBuf);
+        let kind = preview::kind(filename.as_ref()).ok_or_else(|| {
+            anyhow::Error::new(UnsupportedFile).context(format!("{filename} can't be previewed"))
+        })?;
 
-impl Filename {
-    pub fn display(&self) -> std::path::Display {
-        self.0.display()
-    }
-}
+        let data_key = self.controller.data_key(wrapped_key.as_deref())?;
 
-impl<'de> serde::Deserialize<'de> for Filename {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let path = String::deserialize(deserializer)?;
-        let path = std::path::Path::new(&path);
+        let OpenedFile {
+            mut stream, size, ..
+        } = self
+            .controller
+            .open_share_file(&share_config, data_key.as_ref(), &filename, None)
+            .await?;
 
-        let mut components = path.components();
+        let text = if kind == PreviewKind::Text {
+            let limit = preview::TEXT_PREVIEW_LIMIT.0 as usize;
 
-        let filename = components
-            .next()
-            .ok_or_else(|| serde::de::Error::custom("Bad Path"))?;
+            let mut text = Vec::new();
 
-        let filename = if let std::path::Component::Normal(filename) = filename {
-            filename
+            while text.len() < limit {
+                match stream.next().await {
+                    Some(data) => text.extend_from_slice(&data.context("Failed to read data")?),
+                    None => break,
+                }
+            }
+
+            text.truncate(limit);
+
+            Some(String::from_utf8_lossy(&text).into_owned())
         } else {
-            return Err(serde::de::Error::custom("Bad Path"));
+            None
         };
 
-        if components.next().is_some() {
-            return Err(serde::de::Error::custom("Bad Path"));
+        Ok(FilePreview {
+            token,
+            mime: mime_guess::from_path(&filename).first_or_octet_stream(),
+            filename,
+            size,
+            kind,
+            truncated: text.is_some() && size > preview::TEXT_PREVIEW_LIMIT,
+            text,
+            single_file: single_file.is_some(),
+        })
+    }
+
+    /// Gets a thumbnail of a shared image. Errors caused by the token or file not existing
+    /// contain [`TokenNotFound`] or [`ObjectNotFound`], and files which have no thumbnail
+    /// contain [`UnsupportedFile`]
+    pub async fn thumbnail(&self, token: Token, filename: Filename) -> Result<Vec<u8>> {
+        let share_config = self.controller.get_share_config(&token);
+
+        let config = share_config
+            .load()
+            .await
+            .map_err(|err| err.context(TokenNotFound))?;
+
+        if !config.is_visible(&filename) {
+            return Err(anyhow::Error::new(ObjectNotFound)
+                .context(format!("{filename} is not the file of a single file share")));
         }
 
-        Ok(Self(std::path::PathBuf::from(filename)))
-    }
-}
+        let ShareConfig {
+            wrapped_key,
+            end_to_end,
+            ..
+        } = config;
 
-impl AsRef<Path> for Filename {
-    fn as_ref(&self) -> &Path {
-        self.0.as_path()
+        if end_to_end {
+            return Err(anyhow::Error::new(UnsupportedFile)
+                .context("End-to-end encrypted shares have no thumbnails"));
+        }
+
+        if !thumbnails::is_supported(filename.as_ref()) {
+            return Err(anyhow::Error::new(UnsupportedFile)
+                .context(format!("{filename} is not a supported image")));
+        }
+
+        let data_key = self.controller.data_key(wrapped_key.as_deref())?;
+
+        match self
+            .controller
+            .cached_thumbnail(&share_config, data_key.as_ref(), &filename)
+            .await
+        {
+            Ok(thumbnail) => Ok(thumbnail),
+            Err(err) => {
+                tracing::debug!("No cached thumbnail: {err:#}");
+
+                self.controller
+                    .cache_thumbnail(&share_config, data_key.as_ref(), &filename)
+                    .await
+            }
+        }
     }
-}
 
-impl fmt::Display for Filename {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.display().fmt(f)
+    pub async fn open_shared_file(
+        &self,
+        token: Token,
+        filename: Filename,
+        range: Option<(Bound<u64>, Bound<u64>)>,
+    ) -> Result<SharedFile> {
+        let share_config = self.controller.get_share_config(&token);
+
+        let config = share_config.load().await?;
+
+        if !config.is_visible(&filename) {
+            anyhow::bail!("{filename} is not the file of a single file share");
+        }
+
+        let ShareConfig {
+            name,
+            download_rate_limit,
+            webhooks,
+            email_recipients,
+            wrapped_key,
+            ..
+        } = config;
+
+        let data_key = self.controller.data_key(wrapped_key.as_deref())?;
+
+        let OpenedFile {
+            stream,
+            size,
+            range,
+        } = self
+            .controller
+            .open_share_file(&share_config, data_key.as_ref(), &filename, range)
+            .await?;
+
+        let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+
+        let mut throttle = Throttle::default();
+
+        if let Some(bucket) = &self.controller.download_bucket {
+            throttle = throttle.with_bucket(bucket.clone());
+        }
+
+        if let Some(rate) = download_rate_limit {
+            throttle = throttle.with_bucket(self.controller.share_download_bucket(&token, rate));
+        }
+
+        if let Some(rate) = self.config().connection_rate_limit {
+            throttle = throttle.with_rate(ByteCount(rate));
+        }
+
+        // Resumed downloads are counted as complete once they reach the end of the file
+        let reaches_end = range.as_ref().is_none_or(|range| range.end == size.0);
+
+        share_config.record_access(false).await;
+
+        let completion = reaches_end.then(|| DownloadCompletion {
+            controller: self.controller.clone(),
+            token: token.clone(),
+            webhooks,
+            email_recipients,
+            event: Event::ShareDownloaded {
+                token,
+                name,
+                filename: filename.to_string(),
+                size,
+            },
+        });
+
+        Ok(SharedFile {
+            stream,
+            size,
+            range,
+            mime,
+            throttle,
+            completion,
+            transfer: self.controller.metrics.download(),
+        })
     }
 }
 
-pub struct ShareListing {
-    pub name: String,
-    pub token: Token,
-}
+pub fn new_controller(
+    config: AppConfig,
+    storage: Arc<dyn Storage>,
+    webhooks: Webhooks,
+    mailer: Option<Mailer>,
+    metrics: Arc<Metrics>,
+    master_key: Option<MasterKey>,
+) -> (Admin, User) {
+    let download_bucket = config
+        .download_rate_limit
+        .map(|rate| Arc::new(TokenBucket::new(ByteCount(rate))));
 
-pub struct UploadListing {
-    pub name: String,
-    pub token: Token,
-}
+    let controller = Arc::new(Controller {
+        config,
+        storage,
+        token_config_mutex: TokenConfigMutex::default(),
+        activity_mutex: ActivityMutex::default(),
+        download_bucket,
+        share_download_buckets: std::sync::Mutex::default(),
+        webhooks,
+        mailer,
+        metrics,
+        master_key,
+        imports: Imports::default(),
+        space_ledger: SpaceLedgerMutex::default(),
+    });
 
-struct ShareDirectoryEntry {
-    name: String,
-    size: ByteCount,
+    (
+        Admin {
+            controller: controller.clone(),
+        },
+        User { controller },
+    )
 }
 
-#[derive(askama::Template)]
-#[template(path = "user_share_directory_listing.html")]
-pub struct ShareDirectoryListing {
-    name: String,
-    files: Vec<ShareDirectoryEntry>,
-}
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use rand::Rng;
 
-#[derive(Clone)]
-pub struct Admin {
-    controller: Arc<Controller>,
-}
+    use super::*;
 
-impl Admin {
-    pub fn config(&self) -> &AppConfig {
-        &self.controller.config
-    }
+    const UPLOAD_CONFIG: &str = r#"name = "Replies"
+expiry = 2030-01-02T03:04:00+00:00
+space_quota = 1000
+notes = "Sent by post"
+tags = ["blue", "red"]
+email_recipients = ["admin@example.com"]
 
-    pub async fn current_shares(&self) -> Result<Vec<ShareListing>> {
-        let shares_directory = self.config().shares_directory();
+[restrictions]
+allowed_types = [".pdf"]
 
-        let mut share_listings = Vec::new();
+[[webhooks]]
+url = "http://localhost/hook"
+"#;
 
-        for entry in std::fs::read_dir(&shares_directory)
-            .with_context(|| format!("Failed to read {}", shares_directory.display()))?
-        {
-            let entry = entry.with_context(|| {
-                format!("Failed to read entry in {}", shares_directory.display())
-            })?;
-            let token = Token(entry.file_name().to_string_lossy().into_owned());
+    #[test]
+    fn token_details_are_stored_alongside_the_config() {
+        let config = toml::from_str::<UploadConfig>(UPLOAD_CONFIG).unwrap();
 
-            let name = match self
-                .controller
-                .get_token_config::<ShareConfig>(&token)
-                .load()
-                .await
-            {
-                Ok(token) => token.name,
-                Err(err) => {
-                    tracing::warn!("{err:#}");
-                    continue;
-                }
-            };
+        assert_eq!(config.details.notes, "Sent by post");
+        assert_eq!(config.details.tags, ["blue", "red"]);
+        assert_eq!(config.space_quota, ByteCount(1000));
+        assert_eq!(config.webhooks.len(), 1);
 
-            share_listings.push(ShareListing { name, token });
-        }
+        assert_eq!(toml::to_string(&config).unwrap(), UPLOAD_CONFIG);
+    }
 
-        share_listings.sort_by(|a, b| a.name.cmp(&b.name));
+    #[test]
+    fn token_details_are_optional() {
+        let config = toml::from_str::<ExchangeConfig>(
+            r#"name = "Exchange"
+expiry = 2030-01-02T03:04:00+00:00
+share = "share"
+upload = "upload"
+"#,
+        )
+        .unwrap();
 
-        Ok(share_listings)
+        assert!(config.details.notes.is_empty());
+        assert!(config.details.tags.is_empty());
     }
 
-    pub async fn new_share_token(&self, config: ShareConfig) -> Result<Token> {
-        let token = Token::new()?;
+    /// A controller whose files are stored in a new directory, as the server creates them
+    fn controller(args: &[&str]) -> (Arc<Controller>, PathBuf) {
+        let files = std::env::temp_dir().join(format!(
+            "file-sharer-controller-{:016X}",
+            rand::thread_rng().gen::<u64>()
+        ));
 
-        self.controller
-            .get_share_config(&token)
-            .create(&config)
-            .await?;
+        std::fs::create_dir_all(&files).unwrap();
 
-        Ok(token)
+        let config = AppConfig::parse_from(
+            ["file-sharer", "--files", files.to_str().unwrap()]
+                .iter()
+                .chain(args),
+        );
+
+        for directory in [&config.shares, &config.uploads, &config.exchanges] {
+            std::fs::create_dir_all(files.join(directory)).unwrap();
+        }
+
+        let storage = storage::new(&config).unwrap();
+        let webhooks = Webhooks::new(config.webhook_queue_directory(), Vec::new()).unwrap();
+
+        let (admin, _) = new_controller(
+            config,
+            storage,
+            webhooks,
+            None,
+            Arc::new(Metrics::new().unwrap()),
+            None,
+        );
+
+        (admin.controller, files)
     }
 
-    pub async fn current_share_config(&self, token: &Token) -> Result<ShareConfig> {
-        self.controller.get_share_config(token).load().await
+    fn rejection(err: &anyhow::Error) -> QuotaRejection {
+        err.downcast_ref::<QuotaExceeded>()
+            .unwrap_or_else(|| panic!("{err:#} is not a quota rejection"))
+            .rejection
     }
 
-    pub async fn share_files(&self, token: Token, files: Multipart) -> Result<()> {
-        let token_config = self.controller.get_share_config(&token);
+    #[test]
+    fn disallowed_file_types_are_rejected() {
+        let restrictions = UploadRestrictions {
+            allowed_types: vec![String::from(".pdf"), String::from("image/*")],
+            ..UploadRestrictions::default()
+        };
 
-        if Timestamp::now()? > token_config.load().await?.expiry {
-            anyhow::bail!("Token has expired");
+        const PDF: &[u8] = b"%PDF-1.7\n";
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        restrictions
+            .check_file_type(Path::new("report.pdf"), PDF)
+            .unwrap();
+        restrictions
+            .check_file_type(Path::new("photo.png"), PNG)
+            .unwrap();
+
+        for (filename, header) in [
+            ("page.html", b"<html></html>".as_slice()),
+            // Named as an allowed type, but containing something else
+            ("photo.png", PDF),
+            ("report.pdf", PNG),
+        ] {
+            let err = restrictions
+                .check_file_type(Path::new(filename), header)
+                .unwrap_err();
+
+            assert!(matches!(rejection(&err), QuotaRejection::FileType));
+            assert!(
+                err.to_string().ends_with("Allowed types: .pdf, image/*"),
+                "{err}"
+            );
         }
+    }
 
-        let mut actual_file_size = ByteCount(0);
+    #[tokio::test]
+    async fn file_count_is_limited_and_names_are_not_reused() {
+        let (controller, files) = controller(&[]);
+        let storage = controller.storage.as_ref();
 
-        NewFile::from_multipart(token_config.files_directory(), files, &mut actual_file_size).await
-    }
+        storage.create_directory("files").await.unwrap();
+        storage.write("files/a.txt", Vec::new()).await.unwrap();
 
-    pub async fn current_uploads(&self) -> Result<Vec<UploadListing>> {
-        let uploads_directory = self.config().uploads_directory();
+        let mut existing_files = ExistingFiles::list(storage, "files").await.unwrap();
 
-        let mut upload_listings = Vec::new();
+        assert!(existing_files.contains(Path::new("a.txt")));
 
-        for entry in std::fs::read_dir(&uploads_directory)
-            .with_context(|| format!("Failed to read {}", uploads_directory.display()))?
-        {
-            let entry = entry.with_context(|| {
-                format!("Failed to read entry in {}", uploads_directory.display())
-            })?;
-            let token = Token(entry.file_name().to_string_lossy().into_owned());
-            let name = match self
-                .controller
-                .get_token_config::<UploadConfig>(&token)
-                .load()
-                .await
-            {
-                Ok(token) => token.name,
-                Err(err) => {
-                    tracing::warn!("{err:#}");
-                    continue;
-                }
-            };
+        let err = existing_files.add(Path::new("a.txt"), None).unwrap_err();
+        assert!(err.is::<FileExists>());
 
-            upload_listings.push(UploadListing { name, token });
-        }
+        existing_files.add(Path::new("b.txt"), Some(2)).unwrap();
 
-        upload_listings.sort_by(|a, b| a.name.cmp(&b.name));
+        let err = existing_files.add(Path::new("c.txt"), Some(2)).unwrap_err();
+        assert!(matches!(rejection(&err), QuotaRejection::FileCount));
 
-        Ok(upload_listings)
-    }
+        // The same name can't be used twice within an upload either
+        let err = existing_files.add(Path::new("b.txt"), None).unwrap_err();
+        assert!(err.is::<FileExists>());
 
-    pub async fn new_upload_token(&self, config: UploadConfig) -> Result<Token> {
-        let token = Token::new()?;
+        std::fs::remove_dir_all(files).unwrap();
+    }
 
-        let name = if config.name.is_empty() {
-            token.0.clone()
-        } else {
-            config.name
-        };
+    #[tokio::test]
+    async fn uploads_are_limited_by_file_size_and_space_quotas() {
+        let (controller, files) = controller(&["--storage-quota", "1000"]);
+        let storage = controller.storage.as_ref();
 
-        let config = UploadConfig { name, ..config };
+        let mut reservation = controller
+            .reserve_space(StorageArea::Uploads)
+            .await
+            .unwrap()
+            .with_token("uploads/token", ByteCount(500));
 
-        self.controller
-            .get_upload_config(&token)
-            .create(&config)
-            .await?;
+        let mut file = NewFile::new(storage, "a", Path::new("a"), Some(ByteCount(300)), None)
+            .await
+            .unwrap();
 
-        Ok(token)
-    }
+        file.write_all(&[0; 200], Some(&mut reservation))
+            .await
+            .unwrap();
 
-    pub async fn current_upload_config(&self, token: &Token) -> Result<UploadConfig> {
-        self.controller.get_upload_config(token).load().await
-    }
-}
+        let err = file
+            .write_all(&[0; 200], Some(&mut reservation))
+            .await
+            .unwrap_err();
+        assert!(matches!(rejection(&err), QuotaRejection::FileSize));
 
-#[derive(Clone)]
-pub struct User {
-    controller: Arc<Controller>,
-}
+        let mut file = NewFile::new(storage, "b", Path::new("b"), None, None)
+            .await
+            .unwrap();
 
-impl User {
-    pub fn config(&self) -> &AppConfig {
-        &self.controller.config
-    }
+        file.write_all(&[0; 200], Some(&mut reservation))
+            .await
+            .unwrap();
 
-    pub async fn upload_files(
-        &self,
-        token: Token,
-        content_length: u64,
-        files: Multipart,
-    ) -> Result<()> {
-        let request_size = ByteCount(content_length);
+        // Only 100 bytes of the token's quota are left
+        let err = file
+            .write_all(&[0; 200], Some(&mut reservation))
+            .await
+            .unwrap_err();
+        assert!(matches!(rejection(&err), QuotaRejection::Space));
+        assert_eq!(err.to_string(), "Out of Space");
 
-        let token_config = self.controller.get_token_config::<UploadConfig>(&token);
+        drop(reservation);
 
-        token_config
-            .update(|token_config| {
-                if Timestamp::now()? > token_config.expiry {
-                    anyhow::bail!("Token has expired");
-                }
+        // Nothing was stored, so all of the space is returned
+        {
+            let ledger = controller.space_ledger.lock().unwrap();
 
-                token_config.space_quota = token_config
-                    .space_quota
-                    .checked_sub(request_size)
-                    .context("Out of Space")?;
+            assert_eq!(ledger.reserved, ByteCount(0));
+            assert!(ledger.tokens.is_empty());
+        }
 
-                Ok(())
-            })
-            .await?;
+        let mut reservation = controller.reserve_space(StorageArea::Shares).await.unwrap();
 
-        let mut actual_file_size = ByteCount(0);
+        reservation.write(ByteCount(600)).unwrap();
 
-        let write_result =
-            NewFile::from_multipart(token_config.files_directory(), files, &mut actual_file_size)
-                .await;
+        let err = reservation.write(ByteCount(600)).unwrap_err();
+        assert!(matches!(rejection(&err), QuotaRejection::Space));
+        assert_eq!(err.to_string(), "Storage is full");
 
-        token_config
-            .update(|token_config| {
-                token_config.space_quota += request_size.saturating_sub(actual_file_size);
-                Ok(())
-            })
-            .await?;
+        std::fs::remove_dir_all(files).unwrap();
+    }
 
-        write_result
+    /// Contents which differ at every offset, so that misplaced ranges are noticed
+    fn contents(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index % 251) as u8).collect()
     }
 
-    pub async fn directory_listing(&self, token: Token) -> Result<ShareDirectoryListing> {
-        let share_config = self.controller.get_share_config(&token);
+    #[tokio::test]
+    async fn ranges_are_read_across_chunk_boundaries() {
+        // The size of the chunks which files are encrypted in
+        const CHUNK: u64 = 64 * 1024;
 
-        let name = share_config.load().await?.name;
+        let (controller, files) = controller(&[]);
+        let contents = contents(3 * CHUNK as usize + 123);
+        let size = contents.len() as u64;
 
-        let files_directory = share_config.files_directory();
+        for data_key in [None, Some(DataKey::generate())] {
+            let data_key = data_key.as_ref();
 
-        let files = std::fs::read_dir(&files_directory)
-            .with_context(|| format!("Failed to read directory {}", files_directory.display()))?
-            .map(|entry| {
-                let entry = entry.with_context(|| {
-                    format!("Failed to read entry in {}", files_directory.display())
-                })?;
+            let mut writer = create_file(controller.storage.as_ref(), "file", data_key)
+                .await
+                .unwrap();
 
-                let name = entry.file_name().to_string_lossy().into_owned();
+            // Written in pieces which don't line up with chunks
+            for piece in contents.chunks(10_000) {
+                writer.write(piece).await.unwrap();
+            }
 
-                let size = ByteCount(
-                    entry
-                        .metadata()
-                        .with_context(|| {
-                            format!("Failed to read metadata for {}", entry.path().display())
-                        })?
-                        .len(),
+            writer.finish().await.unwrap();
+
+            let opened = controller.open_file("file", data_key, None).await.unwrap();
+
+            assert_eq!(opened.size, ByteCount(size));
+            assert_eq!(opened.range, None);
+            assert_eq!(read_stream(opened.stream).await.unwrap(), contents);
+
+            for (requested, expected) in [
+                ((Bound::Included(0), Bound::Included(0)), 0..1),
+                (
+                    (Bound::Included(CHUNK - 1), Bound::Included(CHUNK)),
+                    CHUNK - 1..CHUNK + 1,
+                ),
+                (
+                    (Bound::Included(CHUNK), Bound::Included(2 * CHUNK - 1)),
+                    CHUNK..2 * CHUNK,
+                ),
+                ((Bound::Included(100), Bound::Unbounded), 100..size),
+                ((Bound::Unbounded, Bound::Included(200)), size - 200..size),
+                (
+                    (Bound::Included(size - 1), Bound::Included(size + 100)),
+                    size - 1..size,
+                ),
+            ] {
+                let opened = controller
+                    .open_file("file", data_key, Some(requested))
+                    .await
+                    .unwrap();
+
+                assert_eq!(opened.size, ByteCount(size));
+                assert_eq!(opened.range, Some(expected.clone()));
+                assert_eq!(
+                    read_stream(opened.stream).await.unwrap(),
+                    contents[expected.start as usize..expected.end as usize],
+                    "{expected:?} of {} file",
+                    if data_key.is_some() {
+                        "an encrypted"
+                    } else {
+                        "a plain"
+                    },
                 );
+            }
 
-                Ok(ShareDirectoryEntry { name, size })
-            })
-            .collect::<Result<Vec<_>>>()?;
+            let Err(err) = controller
+                .open_file(
+                    "file",
+                    data_key,
+                    Some((Bound::Included(size), Bound::Unbounded)),
+                )
+                .await
+            else {
+                panic!("A range beyond the end of the file was satisfied");
+            };
+
+            assert!(err.is::<RangeNotSatisfiable>());
+        }
 
-        Ok(ShareDirectoryListing { name, files })
+        std::fs::remove_dir_all(files).unwrap();
     }
 
-    pub async fn open_shared_file(
-        &self,
-        token: Token,
-        filename: Filename,
-    ) -> Result<(tokio::fs::File, std::fs::Metadata, mime_guess::Mime)> {
-        let path = self
-            .controller
-            .get_share_config(&token)
-            .files_directory()
-            .join(filename);
+    fn listing(token: &str, name: &str, notes: &str, tags: &[&str]) -> TokenListing {
+        TokenListing::new(
+            token.parse().unwrap(),
+            String::from(name),
+            Timestamp::parse_rfc3339("2030-01-01T00:00:00Z").unwrap(),
+            TokenDetails {
+                notes: String::from(notes),
+                tags: tags.iter().map(|tag| String::from(*tag)).collect(),
+            },
+        )
+    }
 
-        let file = tokio::fs::File::open(&path)
-            .await
-            .with_context(|| format!("Failed to open {}", path.display()))?;
+    #[test]
+    fn tokens_are_searched_and_filtered() {
+        let listings = [
+            listing("202401020304_A", "Holiday Photos", "", &["family"]),
+            listing("202403040506_B", "Contract", "Signed by Acme", &["work"]),
+            listing("legacy", "Old Share", "", &["work", "archive"]),
+        ];
+
+        let now = Timestamp::parse_rfc3339("2029-01-01T00:00:00Z").unwrap();
+
+        let matching = |filter: TokenFilter| {
+            listings
+                .iter()
+                .filter(|listing| filter.matches(listing, now))
+                .map(|listing| listing.token.as_str())
+                .collect::<Vec<_>>()
+        };
 
-        let metadata = file
-            .metadata()
-            .await
-            .with_context(|| format!("Failed to get metadata for {}", path.display()))?;
+        let search = |search: &str| TokenFilter {
+            search: Some(String::from(search)),
+            ..TokenFilter::default()
+        };
 
-        let mime = mime_guess::from_path(path).first_or_octet_stream();
+        assert_eq!(matching(TokenFilter::default()).len(), 3);
+        assert_eq!(matching(search("PHOTO")), ["202401020304_A"]);
+        assert_eq!(matching(search("acme")), ["202403040506_B"]);
+        assert_eq!(matching(search("arch")), ["legacy"]);
+        assert_eq!(
+            matching(search(&listings[1].token.log_id())),
+            ["202403040506_B"]
+        );
 
-        Ok((file, metadata, mime))
-    }
-}
+        assert_eq!(
+            matching(TokenFilter {
+                tag: Some(String::from("work")),
+                ..TokenFilter::default()
+            }),
+            ["202403040506_B", "legacy"]
+        );
 
-pub fn new_controller(config: AppConfig) -> (Admin, User) {
-    let controller = Arc::new(Controller {
-        config,
-        token_config_mutex: TokenConfigMutex::new(TokenConfigMutexCore),
-    });
+        // Tokens which don't start with their creation time match no creation date
+        assert_eq!(
+            matching(TokenFilter {
+                created_from: Some("2024-02-01".parse().unwrap()),
+                ..TokenFilter::default()
+            }),
+            ["202403040506_B"]
+        );
+        assert_eq!(
+            matching(TokenFilter {
+                created_to: Some("2024-01-02".parse().unwrap()),
+                ..TokenFilter::default()
+            }),
+            ["202401020304_A"]
+        );
 
-    (
-        Admin {
-            controller: controller.clone(),
-        },
-        User { controller },
-    )
+        assert_eq!(
+            matching(TokenFilter {
+                status: Some(TokenStatus::Active),
+                ..TokenFilter::default()
+            })
+            .len(),
+            3
+        );
+
+        let later = Timestamp::parse_rfc3339("2031-01-01T00:00:00Z").unwrap();
+
+        assert!(listings.iter().all(|listing| {
+            TokenFilter {
+                status: Some(TokenStatus::Expired),
+                ..TokenFilter::default()
+            }
+            .matches(listing, later)
+        }));
+    }
 }
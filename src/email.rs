@@ -0,0 +1,354 @@
+use anyhow::{Context, Result};
+use askama::Template;
+use lettre::{
+    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+
+use crate::{
+    controller::ByteCount,
+    events::{Event, UploadedFile},
+    AppConfig,
+};
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+pub enum SmtpSecurity {
+    /// Plain text. Only suitable for a local mail server
+    None,
+    /// Upgrade the connection to TLS using STARTTLS
+    Starttls,
+    /// Connect using TLS
+    Tls,
+}
+
+#[derive(Template)]
+#[template(source = "Files uploaded to {{name}}", ext = "txt")]
+struct UploadCompletedSubject<'a> {
+    name: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email_upload_completed.txt")]
+struct UploadCompletedBody<'a> {
+    name: &'a str,
+    /// The token's log ID, as the token itself would let anyone who sees the email use it
+    token_id: String,
+    files: &'a [UploadedFile],
+    total_size: ByteCount,
+}
+
+#[derive(Template)]
+#[template(source = "{{filename}} downloaded from {{name}}", ext = "txt")]
+struct ShareDownloadedSubject<'a> {
+    name: &'a str,
+    filename: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email_share_downloaded.txt")]
+struct ShareDownloadedBody<'a> {
+    name: &'a str,
+    token_id: String,
+    filename: &'a str,
+    size: ByteCount,
+}
+
+fn render_event(event: &Event) -> Result<(String, String)> {
+    Ok(match event {
+        Event::UploadCompleted { token, name, files } => {
+            let mut total_size = ByteCount(0);
+
+            for file in files {
+                total_size += file.size;
+            }
+
+            (
+                UploadCompletedSubject { name }.render()?,
+                UploadCompletedBody {
+                    name,
+                    token_id: token.log_id(),
+                    files,
+                    total_size,
+                }
+                .render()?,
+            )
+        }
+        Event::ShareDownloaded {
+            token,
+            name,
+            filename,
+            size,
+        } => (
+            ShareDownloadedSubject { name, filename }.render()?,
+            ShareDownloadedBody {
+                name,
+                token_id: token.log_id(),
+                filename,
+                size: *size,
+            }
+            .render()?,
+        ),
+    })
+}
+
+/// Sends emails about events to the recipients chosen for each token
+#[derive(Clone)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: lettre::message::Mailbox,
+}
+
+impl Mailer {
+    /// Returns `None` if no SMTP server is configured
+    pub fn new(config: &AppConfig) -> Result<Option<Self>> {
+        let server = match &config.smtp_server {
+            Some(server) => server,
+            None => return Ok(None),
+        };
+
+        let mut builder = match config.smtp_security {
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(server),
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(server)
+                .with_context(|| format!("Bad SMTP server {server}"))?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(server)
+                .with_context(|| format!("Bad SMTP server {server}"))?,
+        };
+
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+
+        if let Some(username) = &config.smtp_username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                config.smtp_password.clone().unwrap_or_default(),
+            ));
+        }
+
+        let from = config
+            .smtp_from
+            .parse()
+            .with_context(|| format!("Bad from address {}", config.smtp_from))?;
+
+        Ok(Some(Self {
+            transport: builder.build(),
+            from,
+        }))
+    }
+
+    async fn send_now(&self, recipients: &[String], event: &Event) -> Result<()> {
+        let (subject, body) = render_event(event).context("Failed to render email")?;
+
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+
+        for recipient in recipients {
+            message = message.to(recipient
+                .parse()
+                .with_context(|| format!("Bad email address {recipient}"))?);
+        }
+
+        let message = message.body(body).context("Failed to build email")?;
+
+        self.transport
+            .send(message)
+            .await
+            .context("Failed to send email")?;
+
+        Ok(())
+    }
+
+    /// Sends the email in the background
+    pub fn send(&self, recipients: &[String], event: &Event) {
+        if recipients.is_empty() {
+            return;
+        }
+
+        let mailer = self.clone();
+        let recipients = recipients.to_vec();
+        let event = event.clone();
+
+        tokio::spawn(async move {
+            match mailer.send_now(&recipients, &event).await {
+                Ok(()) => tracing::debug!("Sent {} email to {recipients:?}", event.kind()),
+                Err(err) => tracing::error!("{err:#}"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread::JoinHandle,
+    };
+
+    use clap::Parser;
+
+    use super::*;
+
+    /// The commands received by a fake SMTP server, and the message, if one was sent
+    #[derive(Default)]
+    struct Transcript {
+        commands: Vec<String>,
+        message: Option<String>,
+    }
+
+    /// Accepts one connection, rejecting recipients at `rejected.example`
+    fn start() -> (u16, JoinHandle<Transcript>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut transcript = Transcript::default();
+
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+            let mut line = String::new();
+
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_owned();
+                line.clear();
+
+                let reply: &[u8] = if command.starts_with("EHLO") {
+                    b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+                } else if command.starts_with("AUTH") {
+                    b"235 Authenticated\r\n"
+                } else if command.contains("@rejected.example") {
+                    b"550 No such user\r\n"
+                } else if command == "DATA" {
+                    writer.write_all(b"354 Go ahead\r\n").unwrap();
+
+                    let mut message = String::new();
+
+                    while reader.read_line(&mut line).unwrap() > 0 && line != ".\r\n" {
+                        message.push_str(&line);
+                        line.clear();
+                    }
+
+                    line.clear();
+                    transcript.message = Some(message);
+
+                    b"250 Queued\r\n"
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    transcript.commands.push(command);
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+
+                writer.write_all(reply).unwrap();
+                transcript.commands.push(command);
+            }
+
+            transcript
+        });
+
+        (port, server)
+    }
+
+    fn mailer(port: u16) -> Mailer {
+        let config = AppConfig::parse_from([
+            "file-sharer",
+            "--smtp-server",
+            "127.0.0.1",
+            "--smtp-port",
+            &port.to_string(),
+            "--smtp-security",
+            "none",
+            "--smtp-username",
+            "user",
+            "--smtp-password",
+            "password",
+            "--smtp-from",
+            "Sharer <sharer@example.com>",
+        ]);
+
+        Mailer::new(&config).unwrap().unwrap()
+    }
+
+    fn event() -> Event {
+        Event::UploadCompleted {
+            token: "20240102_0304_0123456789ABCDEF0123456789ABCDEF"
+                .parse()
+                .unwrap(),
+            name: String::from("Photos"),
+            files: vec![UploadedFile {
+                name: String::from("photo.jpg"),
+                size: ByteCount(1234),
+                sha256: String::new(),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn email_is_sent() {
+        let (port, server) = start();
+
+        mailer(port)
+            .send_now(
+                &[
+                    String::from("alice@example.com"),
+                    String::from("bob@example.com"),
+                ],
+                &event(),
+            )
+            .await
+            .unwrap();
+
+        let transcript = server.join().unwrap();
+
+        // Sends "\0user\0password" in base64
+        assert!(transcript
+            .commands
+            .contains(&String::from("AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=")));
+
+        for command in [
+            "MAIL FROM:<sharer@example.com>",
+            "RCPT TO:<alice@example.com>",
+            "RCPT TO:<bob@example.com>",
+        ] {
+            assert!(
+                transcript.commands.contains(&String::from(command)),
+                "{command} wasn't sent"
+            );
+        }
+
+        let message = transcript.message.unwrap();
+
+        assert!(message.contains("Subject: Files uploaded to Photos\r\n"));
+        assert!(message.contains("To: alice@example.com, bob@example.com\r\n"));
+        assert!(message.contains("1 file(s), totalling 1234 bytes"));
+        assert!(message.contains("photo.jpg (1234 bytes)"));
+
+        let Event::UploadCompleted { token, .. } = event() else {
+            unreachable!()
+        };
+
+        // Joins lines which were wrapped by quoted-printable encoding
+        let body = message.replace("=\r\n", "");
+
+        assert!(body.contains(&token.log_id()));
+        assert!(!body.contains(token.as_str()));
+    }
+
+    #[tokio::test]
+    async fn rejected_recipient_fails() {
+        let (port, server) = start();
+
+        let result = mailer(port)
+            .send_now(&[String::from("nobody@rejected.example")], &event())
+            .await;
+
+        assert!(result.is_err());
+
+        let transcript = server.join().unwrap();
+
+        assert!(transcript.message.is_none());
+    }
+}
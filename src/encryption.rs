@@ -0,0 +1,336 @@
+use std::{ops::Range, path::Path};
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+use futures_util::StreamExt;
+
+use crate::{
+    controller::ByteCount,
+    storage::{ByteStream, ObjectWriter, Storage},
+};
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 24;
+const TAG_LENGTH: usize = 16;
+
+/// Identifies encrypted files, and the format they are encrypted in
+const MAGIC: &[u8] = b"FSE1";
+
+/// Each chunk's nonce is this random per-file prefix, followed by the chunk's index
+/// and a flag marking the final chunk, so that chunks can't be reordered or truncated
+const NONCE_PREFIX_LENGTH: usize = NONCE_LENGTH - 8 - 1;
+
+const HEADER_LENGTH: usize = MAGIC.len() + NONCE_PREFIX_LENGTH;
+
+/// Files are encrypted in chunks of this many bytes, so that any range can be decrypted
+/// without decrypting the whole file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LENGTH;
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    use rand::RngCore;
+
+    let mut bytes = [0; N];
+
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    bytes
+}
+
+/// Encrypts the data keys of each token. Kept in a file outside of the storage
+pub struct MasterKey(XChaCha20Poly1305);
+
+impl MasterKey {
+    fn from_file_contents(path: &Path, contents: &str) -> Result<Self> {
+        let key = hex::decode(contents.trim())
+            .ok()
+            .filter(|key| key.len() == KEY_LENGTH)
+            .with_context(|| format!("{} does not contain a valid key", path.display()))?;
+
+        Ok(Self(XChaCha20Poly1305::new_from_slice(&key).map_err(
+            |_| anyhow::anyhow!("{} does not contain a valid key", path.display()),
+        )?))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => anyhow::bail!(
+                "Master key file {} doesn't exist. Create it with the generate-master-key subcommand",
+                path.display()
+            ),
+            contents => contents
+                .with_context(|| format!("Failed to read master key from {}", path.display()))?,
+        };
+
+        Self::from_file_contents(path, &contents)
+    }
+
+    /// Generates a new key and saves it to `path`, which must not already exist
+    pub fn generate(path: &Path) -> Result<Self> {
+        use std::io::Write;
+
+        let contents = hex::encode(random_bytes::<KEY_LENGTH>());
+
+        let mut options = std::fs::OpenOptions::new();
+
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        options
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{contents}"))
+            .with_context(|| format!("Failed to write master key to {}", path.display()))?;
+
+        tracing::info!("Generated new master key in {}", path.display());
+
+        Self::from_file_contents(path, &contents)
+    }
+
+    /// Encrypts a data key so that it can be stored alongside the token config
+    pub fn wrap(&self, DataKey(data_key): &DataKey) -> Result<String> {
+        let nonce = random_bytes::<NONCE_LENGTH>();
+
+        let wrapped_key = self
+            .0
+            .encrypt(XNonce::from_slice(&nonce), data_key.as_slice())
+            .map_err(|_| anyhow::anyhow!("Failed to wrap data key"))?;
+
+        Ok(hex::encode([nonce.as_slice(), &wrapped_key].concat()))
+    }
+
+    pub fn unwrap(&self, wrapped_key: &str) -> Result<DataKey> {
+        let wrapped_key = hex::decode(wrapped_key).context("Bad wrapped data key")?;
+
+        anyhow::ensure!(
+            wrapped_key.len() == NONCE_LENGTH + KEY_LENGTH + TAG_LENGTH,
+            "Bad wrapped data key"
+        );
+
+        let (nonce, wrapped_key) = wrapped_key.split_at(NONCE_LENGTH);
+
+        let data_key = self
+            .0
+            .decrypt(XNonce::from_slice(nonce), wrapped_key)
+            .map_err(|_| {
+                anyhow::anyhow!("Failed to unwrap data key. Is the master key correct?")
+            })?;
+
+        Ok(DataKey(data_key.try_into().unwrap()))
+    }
+}
+
+/// Encrypts the files of a single token
+#[derive(Clone)]
+pub struct DataKey([u8; KEY_LENGTH]);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        Self(random_bytes())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+fn chunk_nonce(prefix: &[u8], index: u64, is_last: bool) -> XNonce {
+    let mut nonce = XNonce::default();
+
+    nonce[..NONCE_PREFIX_LENGTH].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LENGTH..NONCE_LENGTH - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LENGTH - 1] = u8::from(is_last);
+
+    nonce
+}
+
+/// The number of chunks in an encrypted file. Only an empty file has an empty chunk
+fn chunk_count(ByteCount(encrypted_size): ByteCount) -> Result<u64> {
+    let body_size = encrypted_size
+        .checked_sub(HEADER_LENGTH as u64)
+        .filter(|&body_size| body_size >= TAG_LENGTH as u64)
+        .context("Encrypted file is truncated")?;
+
+    Ok(body_size.div_ceil(ENCRYPTED_CHUNK_SIZE as u64))
+}
+
+/// The size of the plaintext of an encrypted file
+pub fn plaintext_size(encrypted_size: ByteCount) -> Result<ByteCount> {
+    let overhead = HEADER_LENGTH as u64 + chunk_count(encrypted_size)? * TAG_LENGTH as u64;
+
+    Ok(ByteCount(encrypted_size.0 - overhead))
+}
+
+/// Encrypts a file as it is written
+pub struct EncryptingWriter {
+    inner: Box<dyn ObjectWriter>,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    chunk_index: u64,
+    buffer: Vec<u8>,
+}
+
+impl EncryptingWriter {
+    pub async fn new(mut inner: Box<dyn ObjectWriter>, data_key: &DataKey) -> Result<Self> {
+        let nonce_prefix = random_bytes();
+
+        inner.write(&[MAGIC, &nonce_prefix].concat()).await?;
+
+        Ok(Self {
+            inner,
+            cipher: data_key.cipher(),
+            nonce_prefix,
+            chunk_index: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    async fn write_chunk(&mut self, length: usize, is_last: bool) -> Result<()> {
+        let chunk = self
+            .cipher
+            .encrypt(
+                &chunk_nonce(&self.nonce_prefix, self.chunk_index, is_last),
+                &self.buffer[..length],
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt chunk"))?;
+
+        self.buffer.drain(..length);
+        self.chunk_index += 1;
+
+        self.inner.write(&chunk).await
+    }
+}
+
+#[async_trait::async_trait]
+impl ObjectWriter for EncryptingWriter {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.buffer.extend_from_slice(data);
+
+        // A full chunk is only written once more data arrives, as the last chunk must be marked
+        while self.buffer.len() > CHUNK_SIZE {
+            self.write_chunk(CHUNK_SIZE, false).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<()> {
+        self.write_chunk(self.buffer.len(), true).await?;
+
+        self.inner.finish().await
+    }
+}
+
+struct Decryption {
+    encrypted: ByteStream,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: Vec<u8>,
+    chunk_index: u64,
+    last_chunk_index: u64,
+    buffer: Vec<u8>,
+    /// Bytes at the start of the first chunk which are before the requested range
+    skip: usize,
+    remaining: u64,
+}
+
+impl Decryption {
+    async fn next_chunk(mut self) -> std::io::Result<Option<(Bytes, Self)>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        while self.buffer.len() < ENCRYPTED_CHUNK_SIZE {
+            match self.encrypted.next().await {
+                Some(data) => self.buffer.extend_from_slice(&data?),
+                None => break,
+            }
+        }
+
+        let length = self.buffer.len().min(ENCRYPTED_CHUNK_SIZE);
+
+        let mut chunk = self
+            .cipher
+            .decrypt(
+                &chunk_nonce(
+                    &self.nonce_prefix,
+                    self.chunk_index,
+                    self.chunk_index == self.last_chunk_index,
+                ),
+                Payload {
+                    msg: &self.buffer[..length],
+                    aad: &[],
+                },
+            )
+            .map_err(|_| std::io::Error::other("Failed to decrypt chunk"))?;
+
+        self.buffer.drain(..length);
+        self.chunk_index += 1;
+
+        chunk.drain(..self.skip.min(chunk.len()));
+        chunk.truncate(self.remaining.try_into().unwrap_or(usize::MAX));
+
+        self.skip = 0;
+        self.remaining -= chunk.len() as u64;
+
+        Ok(Some((chunk.into(), self)))
+    }
+}
+
+/// Reads and decrypts part of an encrypted file. `range` is within the plaintext
+pub async fn open(
+    storage: &dyn Storage,
+    key: &str,
+    data_key: &DataKey,
+    encrypted_size: ByteCount,
+    range: Range<u64>,
+) -> Result<ByteStream> {
+    if range.is_empty() {
+        return Ok(Box::pin(futures_util::stream::empty()));
+    }
+
+    let mut header = Vec::new();
+    let mut header_stream = storage.open(key, 0..HEADER_LENGTH as u64).await?;
+
+    while let Some(data) = header_stream.next().await {
+        header.extend_from_slice(&data.with_context(|| format!("Failed to read {key}"))?);
+    }
+
+    anyhow::ensure!(
+        header.len() == HEADER_LENGTH && header.starts_with(MAGIC),
+        "{key} is not encrypted"
+    );
+
+    let first_chunk = range.start / CHUNK_SIZE as u64;
+    let last_chunk = (range.end - 1) / CHUNK_SIZE as u64;
+
+    let chunk_offset = |chunk: u64| HEADER_LENGTH as u64 + chunk * ENCRYPTED_CHUNK_SIZE as u64;
+
+    let encrypted = storage
+        .open(
+            key,
+            chunk_offset(first_chunk)..chunk_offset(last_chunk + 1).min(encrypted_size.0),
+        )
+        .await?;
+
+    let decryption = Decryption {
+        encrypted,
+        cipher: data_key.cipher(),
+        nonce_prefix: header[MAGIC.len()..].to_vec(),
+        chunk_index: first_chunk,
+        last_chunk_index: chunk_count(encrypted_size)? - 1,
+        buffer: Vec::new(),
+        skip: (range.start - first_chunk * CHUNK_SIZE as u64) as usize,
+        remaining: range.end - range.start,
+    };
+
+    Ok(Box::pin(futures_util::stream::try_unfold(
+        decryption,
+        Decryption::next_chunk,
+    )))
+}
@@ -0,0 +1,43 @@
+use crate::controller::{ByteCount, Token};
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct UploadedFile {
+    pub name: String,
+    pub size: ByteCount,
+    /// The SHA-256 checksum of the file, in hex
+    #[serde(default)]
+    pub sha256: String,
+}
+
+/// Tokens grant access to files, so are only sent as their log IDs
+fn serialize_log_id<S: serde::Serializer>(token: &Token, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&token.log_id())
+}
+
+/// Something which has happened to a token, which other systems may wish to be notified of
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    UploadCompleted {
+        #[serde(rename = "token_id", serialize_with = "serialize_log_id")]
+        token: Token,
+        name: String,
+        files: Vec<UploadedFile>,
+    },
+    ShareDownloaded {
+        #[serde(rename = "token_id", serialize_with = "serialize_log_id")]
+        token: Token,
+        name: String,
+        filename: String,
+        size: ByteCount,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::UploadCompleted { .. } => "upload_completed",
+            Event::ShareDownloaded { .. } => "share_downloaded",
+        }
+    }
+}
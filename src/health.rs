@@ -0,0 +1,217 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+
+use crate::{
+    storage::{join_key, Storage},
+    timestamp::Timestamp,
+    AppConfig,
+};
+
+#[derive(serde::Serialize)]
+struct BuildInfo {
+    name: &'static str,
+    version: &'static str,
+    git_commit: &'static str,
+    target: &'static str,
+    profile: &'static str,
+}
+
+const BUILD_INFO: BuildInfo = BuildInfo {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+    git_commit: env!("FILE_SHARER_GIT_COMMIT"),
+    target: env!("FILE_SHARER_TARGET"),
+    profile: env!("FILE_SHARER_PROFILE"),
+};
+
+#[derive(serde::Serialize)]
+struct Check {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new(name: String, result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Self {
+                name,
+                ok: true,
+                error: None,
+            },
+            Err(err) => {
+                tracing::warn!("Readiness check {name} failed: {err:#}");
+
+                Self {
+                    name,
+                    ok: false,
+                    error: Some(format!("{err:#}")),
+                }
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Report {
+    status: &'static str,
+    /// Only reported by the admin app, so that users can't find which version is running
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build: Option<&'static BuildInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    checks: Vec<Check>,
+}
+
+/// How long the public readiness result is reused, so that requests can't be turned into storage traffic
+const PUBLIC_RESULT_DURATION: Duration = Duration::from_secs(10);
+
+/// Checks that the server is able to serve shares and receive uploads
+#[derive(Clone)]
+pub struct Health {
+    storage: Arc<dyn Storage>,
+    directories: Arc<[(&'static str, String)]>,
+    public_result: Arc<Mutex<Option<(Instant, bool)>>>,
+}
+
+impl Health {
+    pub fn new(config: &AppConfig, storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            directories: [
+                ("shares", config.shares_key()),
+                ("uploads", config.uploads_key()),
+            ]
+            .into(),
+            public_result: Arc::default(),
+        }
+    }
+
+    /// Checks that the directory exists and is writable by creating and removing a file in it
+    async fn check_directory(&self, directory: &str) -> Result<()> {
+        use rand::Rng;
+
+        let probe = join_key(
+            directory,
+            &format!(".readyz_{:016X}", rand::thread_rng().gen::<u64>()),
+        );
+
+        self.storage
+            .write(&probe, Vec::new())
+            .await
+            .with_context(|| format!("{directory} is not writable"))?;
+
+        self.storage.delete(&probe).await
+    }
+
+    async fn checks(&self) -> Vec<Check> {
+        let mut checks = Vec::new();
+
+        for (name, directory) in self.directories.iter() {
+            checks.push(Check::new(
+                format!("{name}_directory"),
+                self.check_directory(directory).await,
+            ));
+        }
+
+        checks.push(Check::new(
+            String::from("local_time"),
+            Timestamp::now()
+                .map(|_| ())
+                .context("Failed to determine local time offset"),
+        ));
+
+        checks
+    }
+
+    /// Runs the checks without revealing why one failed, reusing the result for a while
+    async fn is_ready(&self) -> bool {
+        if let Some((checked_at, ready)) = *self.public_result.lock().unwrap() {
+            if checked_at.elapsed() < PUBLIC_RESULT_DURATION {
+                return ready;
+            }
+        }
+
+        let ready = self.checks().await.iter().all(|check| check.ok);
+
+        *self.public_result.lock().unwrap() = Some((Instant::now(), ready));
+
+        ready
+    }
+}
+
+/// The server is running and able to respond, with the version and build
+async fn healthz() -> impl IntoResponse {
+    Json(Report {
+        status: "ok",
+        build: Some(&BUILD_INFO),
+        checks: Vec::new(),
+    })
+}
+
+/// The server is running and able to respond
+async fn public_healthz() -> impl IntoResponse {
+    Json(Report {
+        status: "ok",
+        build: None,
+        checks: Vec::new(),
+    })
+}
+
+fn readiness_report(
+    ready: bool,
+    build: Option<&'static BuildInfo>,
+    checks: Vec<Check>,
+) -> impl IntoResponse {
+    let (status_code, status) = if ready {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+
+    (
+        status_code,
+        Json(Report {
+            status,
+            build,
+            checks,
+        }),
+    )
+}
+
+/// The server is able to serve shares and receive uploads, with the result of each check
+async fn readyz(health: axum::Extension<Health>) -> impl IntoResponse {
+    let checks = health.checks().await;
+
+    readiness_report(
+        checks.iter().all(|check| check.ok),
+        Some(&BUILD_INFO),
+        checks,
+    )
+}
+
+/// The server is able to serve shares and receive uploads
+async fn public_readyz(health: axum::Extension<Health>) -> impl IntoResponse {
+    readiness_report(health.is_ready().await, None, Vec::new())
+}
+
+/// Health checks for the admin app, where `/readyz` reports each check
+pub fn routes(config: &AppConfig, storage: Arc<dyn Storage>) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(axum::Extension(Health::new(config, storage)))
+}
+
+/// Health checks for the user app, which only report whether the server is ready
+pub fn public_routes(config: &AppConfig, storage: Arc<dyn Storage>) -> Router {
+    Router::new()
+        .route("/healthz", get(public_healthz))
+        .route("/readyz", get(public_readyz))
+        .layer(axum::Extension(Health::new(config, storage)))
+}
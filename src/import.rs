@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    controller::{ByteCount, Token},
+    references,
+};
+
+/// A file on the server which is to be copied into a share
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// The name of the file in the share
+    pub name: String,
+    pub size: ByteCount,
+}
+
+async fn source_file(path: PathBuf, roots: Option<&[PathBuf]>) -> Result<SourceFile> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} has no filename", path.display()))?
+        .to_string_lossy()
+        .into_owned();
+
+    // Symlinks are followed, so files within the share roots must be checked individually
+    let (path, size) = match roots {
+        Some(roots) => {
+            let references::Reference { path, size, .. } =
+                references::resolve(roots, &path).await?;
+
+            (path, size)
+        }
+        None => {
+            let metadata = tokio::fs::metadata(&path)
+                .await
+                .with_context(|| format!("Failed to get metadata for {}", path.display()))?;
+
+            (path, ByteCount(metadata.len()))
+        }
+    };
+
+    Ok(SourceFile { path, name, size })
+}
+
+/// The files to import from `path`, which is either a file, or a directory whose files are
+/// imported. Shares have no subdirectories, so subdirectories are skipped. If `roots` is
+/// given, all files must be within them
+pub async fn source_files(path: &Path, roots: Option<&[PathBuf]>) -> Result<Vec<SourceFile>> {
+    let path = match roots {
+        Some(roots) => references::resolve_path(roots, path).await?,
+        None => path.to_owned(),
+    };
+
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .with_context(|| format!("Failed to get metadata for {}", path.display()))?;
+
+    if !metadata.is_dir() {
+        return Ok(vec![source_file(path, roots).await?]);
+    }
+
+    let mut entries = tokio::fs::read_dir(&path)
+        .await
+        .with_context(|| format!("Failed to read directory {}", path.display()))?;
+
+    let mut files = Vec::new();
+
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("Failed to read entry in {}", path.display()))?
+    {
+        let entry_path = entry.path();
+
+        let metadata = tokio::fs::metadata(&entry_path)
+            .await
+            .with_context(|| format!("Failed to get metadata for {}", entry_path.display()))?;
+
+        if metadata.is_dir() {
+            tracing::info!("Skipping directory {}", entry_path.display());
+            continue;
+        }
+
+        files.push(source_file(entry_path, roots).await?);
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(files)
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ImportState {
+    Running,
+    Completed,
+    Failed { error: String },
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct ImportProgress {
+    pub id: usize,
+    pub token: Token,
+    pub source: PathBuf,
+    pub total_files: usize,
+    pub total_size: ByteCount,
+    pub imported_files: usize,
+    pub imported_size: ByteCount,
+    /// The file being copied, while the import is running
+    pub current_file: Option<String>,
+    /// Files which weren't copied, as the share already has a file with the same name
+    pub skipped_files: Vec<String>,
+    #[serde(flatten)]
+    pub state: ImportState,
+}
+
+/// The progress of all imports since the server started
+#[derive(Default)]
+pub struct Imports(std::sync::Mutex<Vec<ImportProgress>>);
+
+impl Imports {
+    fn imports(&self) -> std::sync::MutexGuard<'_, Vec<ImportProgress>> {
+        self.0.lock().unwrap()
+    }
+
+    /// Records the start of an import, returning its ID
+    pub fn start(&self, token: Token, source: &Path, files: &[SourceFile]) -> usize {
+        let mut imports = self.imports();
+
+        let mut total_size = ByteCount(0);
+
+        for file in files {
+            total_size += file.size;
+        }
+
+        let id = imports.len();
+
+        imports.push(ImportProgress {
+            id,
+            token,
+            source: source.to_owned(),
+            total_files: files.len(),
+            total_size,
+            imported_files: 0,
+            imported_size: ByteCount(0),
+            current_file: None,
+            skipped_files: Vec::new(),
+            state: ImportState::Running,
+        });
+
+        id
+    }
+
+    pub fn update(&self, id: usize, f: impl FnOnce(&mut ImportProgress)) {
+        if let Some(import) = self.imports().get_mut(id) {
+            f(import);
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<ImportProgress> {
+        self.imports().get(id).cloned()
+    }
+
+    pub fn of_token(&self, token: &Token) -> Vec<ImportProgress> {
+        self.imports()
+            .iter()
+            .filter(|import| import.token == *token)
+            .cloned()
+            .collect()
+    }
+}
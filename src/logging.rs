@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::Instrument;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
+
+use crate::{controller::Token, AppConfig};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Request IDs supplied by a reverse proxy longer than this are replaced
+const MAXIMUM_REQUEST_ID_LENGTH: usize = 64;
+
+/// Path segments at least this long which could be tokens are logged as token log IDs
+const MINIMUM_TOKEN_LENGTH: usize = 20;
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+pub enum LogFormat {
+    /// Human readable, one line per message
+    Full,
+    /// Human readable, spread over multiple lines
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl From<LogRotation> for tracing_appender::rolling::Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Never => Self::NEVER,
+            LogRotation::Hourly => Self::HOURLY,
+            LogRotation::Daily => Self::DAILY,
+        }
+    }
+}
+
+/// Sets up logging. The returned guard must be held until exit, so that buffered messages are
+/// written to the log file
+pub fn init(config: &AppConfig) -> Result<Option<tracing_appender::non_blocking::WorkerGuard>> {
+    let filter = match &config.log_filter {
+        Some(filter) => {
+            EnvFilter::try_new(filter).with_context(|| format!("Bad log filter {filter:?}"))?
+        }
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+
+    let (writer, guard) = match &config.log_file {
+        Some(log_file) => {
+            let file_name = log_file
+                .file_name()
+                .with_context(|| format!("Bad log file {}", log_file.display()))?;
+
+            let directory = log_file
+                .parent()
+                .filter(|directory| !directory.as_os_str().is_empty())
+                .unwrap_or_else(|| std::path::Path::new("."));
+
+            std::fs::create_dir_all(directory)
+                .with_context(|| format!("Failed to create {}", directory.display()))?;
+
+            let (writer, guard) = tracing_appender::non_blocking(
+                tracing_appender::rolling::RollingFileAppender::new(
+                    config.log_rotation.into(),
+                    directory,
+                    file_name,
+                ),
+            );
+
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(config.log_file.is_none());
+
+    match config.log_format {
+        LogFormat::Full => builder.init(),
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Json => builder.json().init(),
+    }
+
+    Ok(guard)
+}
+
+/// Uses the request ID set by a reverse proxy if it is sensible, otherwise generates a new one
+fn request_id<B>(request: &Request<B>) -> String {
+    use rand::Rng;
+
+    request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAXIMUM_REQUEST_ID_LENGTH
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map_or_else(
+            || format!("{:016X}", rand::thread_rng().gen::<u64>()),
+            String::from,
+        )
+}
+
+/// The path of a request, with anything which could be a token replaced by its log ID
+fn redacted_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            match segment
+                .parse::<Token>()
+                .ok()
+                .filter(|_| segment.len() >= MINIMUM_TOKEN_LENGTH)
+            {
+                Some(token) => token.log_id(),
+                None => segment.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Middleware which runs each request inside a span identified by a request ID, which is also
+/// returned to the client in the `X-Request-Id` header.
+/// The span's `token` field is filled in by the controller once the request's token is known.
+/// Tokens are only logged as their log IDs, so that logs don't grant access to files
+pub async fn request_span<B>(
+    app: &'static str,
+    request: Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    let id = request_id(&request);
+
+    let span = tracing::info_span!(
+        "request",
+        %id,
+        app,
+        method = %request.method(),
+        path = %redacted_path(request.uri().path()),
+        token = tracing::field::Empty,
+    );
+
+    let start = std::time::Instant::now();
+
+    let mut response: Response = next.run(request).instrument(span.clone()).await;
+
+    span.in_scope(|| {
+        tracing::debug!(
+            status = response.status().as_u16(),
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "Request handled"
+        );
+    });
+
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+
+    response
+}
@@ -1,14 +1,34 @@
-use std::path::PathBuf;
+use std::{fmt, net::IpAddr, path::PathBuf, process::ExitCode};
 
 use clap::StructOpt;
 use futures_util::FutureExt;
 
 mod admin_app;
+mod content;
 mod controller;
+mod email;
+mod encryption;
+mod events;
+mod health;
+mod import;
+mod logging;
+mod metrics;
+mod preview;
+mod qr;
+mod rate_limit;
+mod references;
+mod s3;
+mod storage;
+mod throttle;
+mod thumbnails;
 mod timestamp;
 mod user_app;
+mod webhooks;
 
-#[derive(Debug, clap::Parser)]
+/// Held by the server, in the files directory
+const LOCK_FILE: &str = "file-sharer.lock";
+
+#[derive(clap::Parser)]
 #[clap(name = "File Sharer")]
 /// Easily share and upload files, protected by access tokens
 pub struct AppConfig {
@@ -24,6 +44,94 @@ pub struct AppConfig {
     /// Where to store uploads (relative to files)
     uploads: PathBuf,
 
+    #[clap(long, default_value = "exchanges")]
+    /// Where to store exchanges, which offer a share and an upload together (relative to files)
+    exchanges: PathBuf,
+
+    #[clap(long, arg_enum, default_value = "local")]
+    /// Where to store shares and uploads. If S3, "files" is only used for webhook deliveries,
+    /// and credentials are read from the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    /// environment variables
+    storage: storage::StorageBackend,
+
+    #[clap(long)]
+    /// The URL of the S3 compatible object store, e.g. "https://s3.eu-west-2.amazonaws.com"
+    s3_endpoint: Option<String>,
+
+    #[clap(long)]
+    /// The S3 bucket to store shares and uploads in
+    s3_bucket: Option<String>,
+
+    #[clap(long, default_value = "us-east-1")]
+    /// The region of the S3 bucket
+    s3_region: String,
+
+    #[clap(long)]
+    /// Address the S3 bucket by path rather than by subdomain, as required by MinIO
+    s3_path_style: bool,
+
+    #[clap(long)]
+    /// Encrypt the files of new shares and uploads, using keys protected by the master key
+    /// in this file, which is created by the generate-master-key subcommand
+    master_key_file: Option<PathBuf>,
+
+    #[clap(long, default_value = "webhooks")]
+    /// Where to store webhook deliveries which are pending (relative to files)
+    webhook_queue: PathBuf,
+
+    #[clap(long = "share-root")]
+    /// A directory on the server whose files may be added to shares by reference, rather than
+    /// by uploading copies of them. May be given multiple times
+    share_roots: Vec<PathBuf>,
+
+    #[clap(long = "webhook-url")]
+    /// A URL which is notified of all uploads and downloads. May be given multiple times
+    webhook_urls: Vec<String>,
+
+    #[clap(long)]
+    /// The secret used to sign payloads sent to webhook URLs
+    webhook_secret: Option<String>,
+
+    #[clap(long)]
+    /// The SMTP server used to send email notifications. If not set, no emails are sent
+    smtp_server: Option<String>,
+
+    #[clap(long)]
+    /// The port of the SMTP server, if not the default for the chosen security
+    smtp_port: Option<u16>,
+
+    #[clap(long, arg_enum, default_value = "starttls")]
+    /// How to secure the connection to the SMTP server
+    smtp_security: email::SmtpSecurity,
+
+    #[clap(long)]
+    /// The username used to log in to the SMTP server
+    smtp_username: Option<String>,
+
+    #[clap(long)]
+    /// The password used to log in to the SMTP server
+    smtp_password: Option<String>,
+
+    #[clap(long, default_value = "File Sharer <file-sharer@localhost>")]
+    /// The sender of email notifications
+    smtp_from: String,
+
+    #[clap(long)]
+    /// The maximum total size of all shares and uploads, in bytes
+    storage_quota: Option<u64>,
+
+    #[clap(long, default_value = "0")]
+    /// Reject uploads which would leave less than this much free disk space, in bytes
+    min_free_space: u64,
+
+    #[clap(long)]
+    /// The maximum total download rate of the user app, in bytes per second
+    download_rate_limit: Option<u64>,
+
+    #[clap(long)]
+    /// The maximum download rate of each individual download, in bytes per second
+    connection_rate_limit: Option<u64>,
+
     #[clap(long)]
     /// Disable the admin app
     disable_admin_app: bool,
@@ -42,18 +150,183 @@ pub struct AppConfig {
     /// i.e. if behind a reverse proxy, you must rewrite URLs
     user_url_prefix: String,
 
+    #[clap(long)]
+    /// The URL of the root of the user app on a separate origin, e.g. "https://files.example.com",
+    /// which shared files are served from instead of the user app's URL. This stops shared files
+    /// from running scripts against the user app's pages. Both URLs must reach the user app
+    content_url_prefix: Option<String>,
+
     #[clap(long)]
     /// Bind the user app to localhost only (useful for dev)
     user_localhost_only: bool,
+
+    #[clap(long = "trusted-proxy")]
+    /// The address of a reverse proxy whose X-Forwarded-For header is trusted to
+    /// identify the client. May be given multiple times
+    trusted_proxies: Vec<IpAddr>,
+
+    #[clap(long, arg_enum, default_value = "full")]
+    /// How to format log messages
+    log_format: logging::LogFormat,
+
+    #[clap(long)]
+    /// Which log messages to show, e.g. "info" or "file_sharer=debug,hyper=info".
+    /// Defaults to the RUST_LOG environment variable, or "info" if that is not set
+    log_filter: Option<String>,
+
+    #[clap(long)]
+    /// Write log messages to this file instead of stdout
+    log_file: Option<PathBuf>,
+
+    #[clap(long, arg_enum, default_value = "never")]
+    /// How often to start a new log file. Rotated files have the date and time appended
+    log_rotation: logging::LogRotation,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Secrets are replaced, so that the config can be logged
+impl fmt::Debug for AppConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn redacted(secret: &Option<String>) -> Option<&'static str> {
+            secret.as_ref().map(|_| "<redacted>")
+        }
+
+        // Destructured, so that new fields can't be forgotten
+        let Self {
+            files,
+            shares,
+            uploads,
+            exchanges,
+            storage,
+            s3_endpoint,
+            s3_bucket,
+            s3_region,
+            s3_path_style,
+            master_key_file,
+            webhook_queue,
+            share_roots,
+            webhook_urls,
+            webhook_secret,
+            smtp_server,
+            smtp_port,
+            smtp_security,
+            smtp_username,
+            smtp_password,
+            smtp_from,
+            storage_quota,
+            min_free_space,
+            download_rate_limit,
+            connection_rate_limit,
+            disable_admin_app,
+            admin_port,
+            user_port,
+            user_url_prefix,
+            content_url_prefix,
+            user_localhost_only,
+            trusted_proxies,
+            log_format,
+            log_filter,
+            log_file,
+            log_rotation,
+            command,
+        } = self;
+
+        f.debug_struct("AppConfig")
+            .field("files", files)
+            .field("shares", shares)
+            .field("uploads", uploads)
+            .field("exchanges", exchanges)
+            .field("storage", storage)
+            .field("s3_endpoint", s3_endpoint)
+            .field("s3_bucket", s3_bucket)
+            .field("s3_region", s3_region)
+            .field("s3_path_style", s3_path_style)
+            .field("master_key_file", master_key_file)
+            .field("webhook_queue", webhook_queue)
+            .field("share_roots", share_roots)
+            .field("webhook_urls", webhook_urls)
+            .field("webhook_secret", &redacted(webhook_secret))
+            .field("smtp_server", smtp_server)
+            .field("smtp_port", smtp_port)
+            .field("smtp_security", smtp_security)
+            .field("smtp_username", smtp_username)
+            .field("smtp_password", &redacted(smtp_password))
+            .field("smtp_from", smtp_from)
+            .field("storage_quota", storage_quota)
+            .field("min_free_space", min_free_space)
+            .field("download_rate_limit", download_rate_limit)
+            .field("connection_rate_limit", connection_rate_limit)
+            .field("disable_admin_app", disable_admin_app)
+            .field("admin_port", admin_port)
+            .field("user_port", user_port)
+            .field("user_url_prefix", user_url_prefix)
+            .field("content_url_prefix", content_url_prefix)
+            .field("user_localhost_only", user_localhost_only)
+            .field("trusted_proxies", trusted_proxies)
+            .field("log_format", log_format)
+            .field("log_filter", log_filter)
+            .field("log_file", log_file)
+            .field("log_rotation", log_rotation)
+            .field("command", command)
+            .finish()
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Generate a new master key for --master-key-file, then exit
+    GenerateMasterKey {
+        /// Where to store the master key, which mustn't already exist
+        master_key_file: PathBuf,
+    },
+    /// Protect the keys of all shares and uploads with a new master key, then exit. The server
+    /// must be stopped first. Afterwards, set --master-key-file to the new master key
+    RotateMasterKey {
+        #[clap(long)]
+        /// Where to store the new master key, which mustn't already exist unless resuming
+        new_master_key_file: PathBuf,
+        #[clap(long)]
+        /// Use the new master key of an interrupted rotation, rather than generating it
+        resume: bool,
+    },
+    /// Copy a file, or the files in a directory, on the server into a share, then exit. The
+    /// server must be stopped first. Unlike imports from the admin app, the path needn't be
+    /// within a share root
+    Import {
+        /// The token of the share
+        token: controller::Token,
+        /// The file or directory to copy
+        path: PathBuf,
+    },
 }
 
 impl AppConfig {
-    fn shares_directory(&self) -> PathBuf {
-        self.files.join(&self.shares)
+    fn shares_key(&self) -> String {
+        storage::path_key(&self.shares)
+    }
+
+    fn uploads_key(&self) -> String {
+        storage::path_key(&self.uploads)
     }
 
-    fn uploads_directory(&self) -> PathBuf {
-        self.files.join(&self.uploads)
+    fn exchanges_key(&self) -> String {
+        storage::path_key(&self.exchanges)
+    }
+
+    fn webhook_queue_directory(&self) -> PathBuf {
+        self.files.join(&self.webhook_queue)
+    }
+
+    fn global_webhooks(&self) -> Vec<webhooks::Webhook> {
+        self.webhook_urls
+            .iter()
+            .map(|url| webhooks::Webhook {
+                url: url.clone(),
+                secret: self.webhook_secret.clone(),
+            })
+            .collect()
     }
 
     fn token_url(&self, category: &str, token: &controller::Token) -> String {
@@ -61,23 +334,251 @@ impl AppConfig {
 
         format!("{prefix}/{category}/{token}")
     }
+
+    /// Where a shared file is served from, if files are served from a separate origin
+    fn content_url(&self, token: &controller::Token, filename: &str) -> Option<String> {
+        let prefix = self.content_url_prefix.as_deref()?.trim_end_matches('/');
+
+        Some(format!(
+            "{prefix}/share/{token}/{}",
+            content::encode_path_segment(filename)
+        ))
+    }
+
+    /// The absolute URL a shared file is downloaded from
+    fn file_url(&self, token: &controller::Token, filename: &str) -> String {
+        self.content_url(token, filename).unwrap_or_else(|| {
+            format!(
+                "{}/{}",
+                self.token_url("share", token),
+                content::encode_path_segment(filename)
+            )
+        })
+    }
+
+    /// The host and port of the separate origin which files are served from, if any
+    fn content_host(&self) -> Option<String> {
+        url_authority(self.content_url_prefix.as_deref()?)
+    }
+
+    /// The origin of the user app's pages, e.g. "https://example.com"
+    fn user_origin(&self) -> Option<String> {
+        let uri = self.user_url_prefix.parse::<axum::http::Uri>().ok()?;
+
+        Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+    }
+}
+
+fn url_authority(url: &str) -> Option<String> {
+    url.parse::<axum::http::Uri>()
+        .ok()?
+        .authority()
+        .map(|authority| authority.as_str().to_ascii_lowercase())
+}
+
+/// Locks the files directory until the returned file is dropped. The server holds the lock
+/// while it runs, so that commands which change token configs can't run alongside it
+fn lock_files(config: &AppConfig) -> anyhow::Result<std::fs::File> {
+    use anyhow::Context;
+
+    let path = config.files.join(LOCK_FILE);
+
+    std::fs::create_dir_all(&config.files)
+        .with_context(|| format!("Failed to create {}", config.files.display()))?;
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    fs2::FileExt::try_lock_exclusive(&file).with_context(|| {
+        format!(
+            "{} is locked, so the server is already running. Stop it first",
+            path.display()
+        )
+    })?;
+
+    Ok(file)
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
-    tracing_subscriber::fmt::init();
+async fn main() -> ExitCode {
+    let mut config = AppConfig::parse();
+
+    let command = config.command.take();
 
-    let config = AppConfig::parse();
+    let _log_guard = match logging::init(&config) {
+        Ok(log_guard) => log_guard,
+        Err(err) => {
+            eprintln!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
 
     tracing::info!(?config);
 
+    if let Some(Command::GenerateMasterKey { master_key_file }) = &command {
+        return match encryption::MasterKey::generate(master_key_file) {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(err) => {
+                tracing::error!("{err:#}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     let (shutdown_handle, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
     let shutdown_signal = shutdown_signal.map(|_| ()).shared();
 
     let (task_active_handle, mut tasks_complete_signal) = tokio::sync::mpsc::channel::<()>(1);
     let task_active_handle = move |()| drop(task_active_handle);
 
-    let (admin, user) = controller::new_controller(config);
+    let webhooks =
+        match webhooks::Webhooks::new(config.webhook_queue_directory(), config.global_webhooks()) {
+            Ok(webhooks) => webhooks,
+            Err(err) => {
+                tracing::error!("{err:#}");
+                return ExitCode::FAILURE;
+            }
+        };
+
+    let mailer = match email::Mailer::new(&config) {
+        Ok(mailer) => mailer,
+        Err(err) => {
+            tracing::error!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let storage = match storage::new(&config) {
+        Ok(storage) => storage,
+        Err(err) => {
+            tracing::error!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for directory in [
+        config.shares_key(),
+        config.uploads_key(),
+        config.exchanges_key(),
+    ] {
+        if let Err(err) = storage.create_directory(&directory).await {
+            tracing::error!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let metrics = match metrics::Metrics::new() {
+        Ok(metrics) => std::sync::Arc::new(metrics),
+        Err(err) => {
+            tracing::error!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let master_key = match config
+        .master_key_file
+        .as_deref()
+        .map(encryption::MasterKey::load)
+        .transpose()
+    {
+        Ok(master_key) => master_key,
+        Err(err) => {
+            tracing::error!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (admin, user) = controller::new_controller(
+        config,
+        storage,
+        webhooks.clone(),
+        mailer,
+        metrics,
+        master_key,
+    );
+
+    match command {
+        Some(Command::RotateMasterKey {
+            new_master_key_file,
+            resume,
+        }) => {
+            let rotation = async {
+                let _lock = lock_files(admin.config())?;
+
+                let new_master_key = if resume {
+                    encryption::MasterKey::load(&new_master_key_file)?
+                } else {
+                    encryption::MasterKey::generate(&new_master_key_file)?
+                };
+
+                admin.rotate_master_key(&new_master_key).await
+            };
+
+            return match rotation.await {
+                Ok(rotated) => {
+                    tracing::info!(
+                        "Rotated {rotated} keys. Set --master-key-file to {}",
+                        new_master_key_file.display()
+                    );
+                    ExitCode::SUCCESS
+                }
+                Err(err) => {
+                    tracing::error!("{err:#}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Some(Command::Import { token, path }) => {
+            let import = async {
+                let _lock = lock_files(admin.config())?;
+
+                admin.import(token, &path).await
+            };
+
+            return match import.await {
+                Ok(import::ImportProgress {
+                    state: import::ImportState::Completed,
+                    imported_files,
+                    imported_size,
+                    skipped_files,
+                    ..
+                }) => {
+                    tracing::info!(
+                        "Imported {imported_files} files ({imported_size} bytes), skipping {} \
+                         which the share already has",
+                        skipped_files.len()
+                    );
+                    ExitCode::SUCCESS
+                }
+                // Failures are logged as the import stops
+                Ok(_) => ExitCode::FAILURE,
+                Err(err) => {
+                    tracing::error!("{err:#}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        // Handled before the controller is created
+        Some(Command::GenerateMasterKey { .. }) | None => (),
+    }
+
+    let _lock = match lock_files(admin.config()) {
+        Ok(lock) => lock,
+        Err(err) => {
+            tracing::error!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    tokio::spawn(
+        webhooks
+            .run(shutdown_signal.clone())
+            .map(task_active_handle.clone()),
+    );
 
     let admin_app = tokio::spawn(
         admin_app::run(admin, shutdown_signal.clone()).map(task_active_handle.clone()),
@@ -96,4 +597,6 @@ async fn main() {
     drop(shutdown_handle);
 
     tasks_complete_signal.recv().await;
+
+    ExitCode::SUCCESS
 }
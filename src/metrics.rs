@@ -0,0 +1,226 @@
+use std::{sync::Arc, time::Instant};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::MatchedPath,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry,
+};
+
+use crate::controller::{ByteCount, StorageUsage};
+
+/// Why an upload was rejected, for reporting in metrics
+#[derive(Debug, Clone, Copy)]
+pub enum QuotaRejection {
+    /// The token's space quota, or the global storage quota, was exceeded
+    Space,
+    FileSize,
+    FileCount,
+    FileType,
+}
+
+impl QuotaRejection {
+    fn label(self) -> &'static str {
+        match self {
+            QuotaRejection::Space => "space",
+            QuotaRejection::FileSize => "file_size",
+            QuotaRejection::FileCount => "file_count",
+            QuotaRejection::FileType => "file_type",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    bytes_uploaded: IntCounter,
+    bytes_downloaded: IntCounter,
+    active_transfers: IntGaugeVec,
+    failed_token_lookups: IntCounter,
+    quota_rejections: IntCounterVec,
+    storage_usage: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("file_sharer".into()), None)
+            .context("Failed to create metrics registry")?;
+
+        fn register<M: prometheus::core::Collector + Clone + 'static>(
+            registry: &Registry,
+            metric: prometheus::Result<M>,
+        ) -> Result<M> {
+            let metric = metric.context("Failed to create metric")?;
+
+            registry
+                .register(Box::new(metric.clone()))
+                .context("Failed to register metric")?;
+
+            Ok(metric)
+        }
+
+        Ok(Self {
+            requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("http_requests_total", "HTTP requests handled"),
+                    &["app", "route", "status"],
+                ),
+            )?,
+            request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "http_request_duration_seconds",
+                        "Time taken to produce HTTP response headers",
+                    ),
+                    &["app", "route"],
+                ),
+            )?,
+            bytes_uploaded: register(
+                &registry,
+                IntCounter::new("uploaded_bytes_total", "Bytes stored from uploads"),
+            )?,
+            bytes_downloaded: register(
+                &registry,
+                IntCounter::new("downloaded_bytes_total", "Bytes sent from shares"),
+            )?,
+            active_transfers: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("active_transfers", "Uploads and downloads in progress"),
+                    &["direction"],
+                ),
+            )?,
+            failed_token_lookups: register(
+                &registry,
+                IntCounter::new(
+                    "failed_token_lookups_total",
+                    "Requests for tokens or files which don't exist",
+                ),
+            )?,
+            quota_rejections: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("quota_rejections_total", "Uploads rejected by restrictions"),
+                    &["reason"],
+                ),
+            )?,
+            storage_usage: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("storage_usage_bytes", "Storage used"),
+                    &["category"],
+                ),
+            )?,
+            registry,
+        })
+    }
+
+    fn gauge_bytes(&self, category: &str, ByteCount(bytes): ByteCount) {
+        self.storage_usage
+            .with_label_values(&[category])
+            .set(bytes.try_into().unwrap_or(i64::MAX));
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self, storage_usage: &StorageUsage) -> Result<String> {
+        self.gauge_bytes("shares", storage_usage.shares);
+        self.gauge_bytes("uploads", storage_usage.uploads);
+
+        if let Some(available_space) = storage_usage.available_space {
+            self.gauge_bytes("available", available_space);
+        }
+
+        let mut buffer = Vec::new();
+
+        prometheus::TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("Failed to encode metrics")?;
+
+        String::from_utf8(buffer).context("Metrics are not UTF-8")
+    }
+
+    pub fn uploaded(&self, ByteCount(bytes): ByteCount) {
+        self.bytes_uploaded.inc_by(bytes);
+    }
+
+    pub fn failed_token_lookup(&self) {
+        self.failed_token_lookups.inc();
+    }
+
+    pub fn quota_rejection(&self, rejection: QuotaRejection) {
+        self.quota_rejections
+            .with_label_values(&[rejection.label()])
+            .inc();
+    }
+
+    pub fn upload(self: &Arc<Self>) -> Transfer {
+        Transfer::new(self.clone(), "upload")
+    }
+
+    pub fn download(self: &Arc<Self>) -> Transfer {
+        Transfer::new(self.clone(), "download")
+    }
+}
+
+/// An upload or download in progress, counted in the active transfers until dropped
+pub struct Transfer {
+    metrics: Arc<Metrics>,
+    gauge: IntGauge,
+}
+
+impl Transfer {
+    fn new(metrics: Arc<Metrics>, direction: &str) -> Self {
+        let gauge = metrics.active_transfers.with_label_values(&[direction]);
+
+        gauge.inc();
+
+        Self { metrics, gauge }
+    }
+
+    pub fn downloaded(&self, ByteCount(bytes): ByteCount) {
+        self.metrics.bytes_downloaded.inc_by(bytes);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Middleware which counts and times requests
+pub async fn track_requests<B>(
+    metrics: Arc<Metrics>,
+    app: &'static str,
+    request: Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| String::from("unmatched"), |path| path.as_str().into());
+
+    let start = Instant::now();
+
+    let response: Response = next.run(request).await;
+
+    metrics
+        .request_duration
+        .with_label_values(&[app, &route])
+        .observe(start.elapsed().as_secs_f64());
+
+    metrics
+        .requests
+        .with_label_values(&[app, &route, response.status().as_str()])
+        .inc();
+
+    response
+}
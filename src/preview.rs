@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use crate::controller::ByteCount;
+
+/// At most this much of a text file is shown in its preview
+pub const TEXT_PREVIEW_LIMIT: ByteCount = ByteCount(256 * 1024);
+
+/// Types which are text, despite not having a MIME type of `text/*`
+const TEXT_SUBTYPES: &[&str] = &[
+    "json",
+    "javascript",
+    "xml",
+    "x-sh",
+    "x-python",
+    "x-yaml",
+    "toml",
+    "x-toml",
+    "sql",
+];
+
+/// Extensions of source code and configuration files which `mime_guess` doesn't know are text
+const TEXT_EXTENSIONS: &[&str] = &[
+    "rs",
+    "go",
+    "py",
+    "rb",
+    "ts",
+    "tsx",
+    "jsx",
+    "kt",
+    "swift",
+    "cs",
+    "php",
+    "lua",
+    "sql",
+    "toml",
+    "yaml",
+    "yml",
+    "ini",
+    "cfg",
+    "conf",
+    "log",
+    "md",
+    "dockerfile",
+    "gradle",
+    "ps1",
+    "bat",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PreviewKind {
+    Pdf,
+    Image,
+    Audio,
+    Video,
+    Text,
+}
+
+/// How a file can be previewed in the browser, if at all, judging by its name
+pub fn kind(filename: &Path) -> Option<PreviewKind> {
+    let mime = mime_guess::from_path(filename).first();
+
+    let extension = filename
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    if let Some(mime) = &mime {
+        match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("application", "pdf") => return Some(PreviewKind::Pdf),
+            // SVGs may contain scripts, so are shown as text
+            ("image", "svg+xml") => return Some(PreviewKind::Text),
+            ("image", _) => return Some(PreviewKind::Image),
+            ("audio", _) => return Some(PreviewKind::Audio),
+            ("video", _) => return Some(PreviewKind::Video),
+            ("text", _) => return Some(PreviewKind::Text),
+            (_, subtype) if TEXT_SUBTYPES.contains(&subtype) => return Some(PreviewKind::Text),
+            _ => (),
+        }
+    }
+
+    extension
+        .filter(|extension| TEXT_EXTENSIONS.contains(&extension.as_str()))
+        .map(|_| PreviewKind::Text)
+}
+
+/// The highlight.js language of a text file, which is usually its extension
+pub fn language(filename: &Path) -> String {
+    match filename
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .as_deref()
+    {
+        None | Some("txt" | "log" | "csv") => "plaintext".into(),
+        Some("htm") => "html".into(),
+        Some(extension) => extension.into(),
+    }
+}
@@ -0,0 +1,24 @@
+use anyhow::{Context, Result};
+use qrcode::{render::svg, QrCode};
+
+/// The smallest size of the rendered QR code, in pixels
+const MIN_SIZE: u32 = 200;
+
+/// Renders `url` as a QR code, in the form of an SVG which can be embedded in a page
+pub fn svg(url: &str) -> Result<String> {
+    let code = QrCode::new(url.as_bytes())
+        .with_context(|| format!("Failed to generate QR code for {url}"))?;
+
+    let image = code
+        .render::<svg::Color>()
+        .min_dimensions(MIN_SIZE, MIN_SIZE)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
+
+    // The XML declaration is only needed for standalone SVG files
+    Ok(match image.find("<svg") {
+        Some(start) => image[start..].into(),
+        None => image,
+    })
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// How many failed token lookups a client may make before being banned
+const FAILURE_ALLOWANCE: u32 = 10;
+
+/// The length of the first ban. Each subsequent failure doubles the length of the ban
+const INITIAL_BAN: Duration = Duration::from_secs(1);
+
+const MAXIMUM_BAN: Duration = Duration::from_secs(60 * 60);
+
+/// Clients which have not failed a lookup for this long are forgotten
+const FAILURE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Above this many tracked clients, forgotten clients are pruned
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct ClientRecord {
+    failures: u32,
+    last_failure: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl ClientRecord {
+    fn is_forgotten(&self, now: Instant) -> bool {
+        now.duration_since(self.last_failure) > FAILURE_MEMORY
+            && self
+                .banned_until
+                .is_none_or(|banned_until| now > banned_until)
+    }
+}
+
+/// Tracks failed token lookups per client, and bans clients which appear to be guessing tokens
+#[derive(Clone)]
+pub struct RateLimiter {
+    trusted_proxies: Arc<[IpAddr]>,
+    clients: Arc<Mutex<HashMap<IpAddr, ClientRecord>>>,
+}
+
+impl RateLimiter {
+    pub fn new(trusted_proxies: &[IpAddr]) -> Self {
+        Self {
+            trusted_proxies: trusted_proxies.into(),
+            clients: Arc::default(),
+        }
+    }
+
+    /// The address of the client, taking into account `X-Forwarded-For`
+    /// if the peer is a trusted proxy
+    fn client_address(&self, peer_address: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted_proxies.contains(&peer_address) {
+            return peer_address;
+        }
+
+        // Each proxy appends the address it received the request from, so the client is the
+        // rightmost address which isn't one of our proxies
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .find(|address| !self.trusted_proxies.contains(address))
+            .unwrap_or(peer_address)
+    }
+
+    fn ban_remaining(&self, client: IpAddr, now: Instant) -> Option<Duration> {
+        let clients = self.clients.lock().unwrap();
+
+        let banned_until = clients.get(&client)?.banned_until?;
+
+        (banned_until > now).then(|| banned_until - now)
+    }
+
+    fn record_failure(&self, client: IpAddr, now: Instant) {
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() > PRUNE_THRESHOLD {
+            clients.retain(|_, record| !record.is_forgotten(now));
+        }
+
+        let record = clients.entry(client).or_insert(ClientRecord {
+            failures: 0,
+            last_failure: now,
+            banned_until: None,
+        });
+
+        if record.is_forgotten(now) {
+            record.failures = 0;
+        }
+
+        record.failures += 1;
+        record.last_failure = now;
+
+        if let Some(excess_failures) = record.failures.checked_sub(FAILURE_ALLOWANCE) {
+            let ban = INITIAL_BAN
+                .checked_mul(2_u32.saturating_pow(excess_failures))
+                .map_or(MAXIMUM_BAN, |ban| ban.min(MAXIMUM_BAN));
+
+            record.banned_until = Some(now + ban);
+
+            tracing::warn!(
+                %client,
+                failures = record.failures,
+                "Suspected token enumeration, banning client for {}s",
+                ban.as_secs()
+            );
+        }
+    }
+}
+
+/// Middleware which rejects banned clients,
+/// and counts "Not Found" responses as failed token lookups
+pub async fn limit_failed_lookups<B>(
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Response> {
+    let rate_limiter = request
+        .extensions()
+        .get::<RateLimiter>()
+        .cloned()
+        .ok_or_else(|| {
+            tracing::error!("Rate limiter is missing");
+
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    let ConnectInfo(peer_address) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .copied()
+        .ok_or_else(|| {
+            tracing::error!("Client address is missing");
+
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    let client = rate_limiter.client_address(peer_address.ip(), request.headers());
+
+    if let Some(ban_remaining) = rate_limiter.ban_remaining(client, Instant::now()) {
+        tracing::debug!(%client, "Rejecting request from banned client");
+
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                (ban_remaining.as_secs() + 1).to_string(),
+            )],
+        )
+            .into_response());
+    }
+
+    let response = next.run(request).await;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        rate_limiter.record_failure(client, Instant::now());
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    fn fail(rate_limiter: &RateLimiter, times: u32, now: Instant) {
+        for _ in 0..times {
+            rate_limiter.record_failure(CLIENT, now);
+        }
+    }
+
+    #[test]
+    fn clients_are_banned_after_repeated_failures() {
+        let rate_limiter = RateLimiter::new(&[]);
+        let start = Instant::now();
+
+        fail(&rate_limiter, FAILURE_ALLOWANCE - 1, start);
+        assert_eq!(rate_limiter.ban_remaining(CLIENT, start), None);
+
+        fail(&rate_limiter, 1, start);
+        assert_eq!(rate_limiter.ban_remaining(CLIENT, start), Some(INITIAL_BAN));
+
+        // Each further failure doubles the ban
+        fail(&rate_limiter, 1, start);
+        assert_eq!(
+            rate_limiter.ban_remaining(CLIENT, start),
+            Some(INITIAL_BAN * 2)
+        );
+
+        assert_eq!(rate_limiter.ban_remaining(OTHER_CLIENT, start), None);
+
+        // The ban ends
+        assert_eq!(
+            rate_limiter.ban_remaining(CLIENT, start + INITIAL_BAN * 2),
+            None
+        );
+
+        fail(&rate_limiter, 100, start);
+        assert_eq!(rate_limiter.ban_remaining(CLIENT, start), Some(MAXIMUM_BAN));
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let rate_limiter = RateLimiter::new(&[]);
+        let start = Instant::now();
+
+        fail(&rate_limiter, FAILURE_ALLOWANCE, start);
+        assert!(rate_limiter.ban_remaining(CLIENT, start).is_some());
+
+        // The count of failures starts again, rather than the ban being extended
+        let later = start + FAILURE_MEMORY + Duration::from_secs(1);
+
+        fail(&rate_limiter, FAILURE_ALLOWANCE - 1, later);
+        assert_eq!(rate_limiter.ban_remaining(CLIENT, later), None);
+
+        fail(&rate_limiter, 1, later);
+        assert_eq!(rate_limiter.ban_remaining(CLIENT, later), Some(INITIAL_BAN));
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{controller::ByteCount, timestamp::Timestamp};
+
+/// A file on the server which is shared without being copied into storage
+pub struct Reference {
+    /// The path with all symlinks resolved
+    pub path: PathBuf,
+    pub size: ByteCount,
+    pub modified: Option<Timestamp>,
+}
+
+/// Resolves a path, following any symlinks, and checks that it is within one of the allowed
+/// roots
+pub async fn resolve_path(roots: &[PathBuf], path: &Path) -> Result<PathBuf> {
+    if !path.is_absolute() {
+        anyhow::bail!("{} is not an absolute path", path.display());
+    }
+
+    let resolved = tokio::fs::canonicalize(path)
+        .await
+        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+
+    for root in roots {
+        match tokio::fs::canonicalize(root).await {
+            Ok(root) if resolved.starts_with(&root) => return Ok(resolved),
+            Ok(_) => (),
+            Err(err) => tracing::warn!("Failed to resolve share root {}: {err}", root.display()),
+        }
+    }
+
+    anyhow::bail!("{} is not within a share root", resolved.display())
+}
+
+/// Resolves a path with [`resolve_path`], and checks that it is a file. This is done whenever
+/// a reference is used, as the files may have changed
+pub async fn resolve(roots: &[PathBuf], path: &Path) -> Result<Reference> {
+    let resolved = resolve_path(roots, path).await?;
+
+    let metadata = tokio::fs::metadata(&resolved)
+        .await
+        .with_context(|| format!("Failed to get metadata for {}", resolved.display()))?;
+
+    if !metadata.is_file() {
+        anyhow::bail!("{} is not a file", resolved.display());
+    }
+
+    Ok(Reference {
+        path: resolved,
+        size: ByteCount(metadata.len()),
+        modified: metadata.modified().ok().map(Timestamp::from),
+    })
+}
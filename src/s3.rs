@@ -0,0 +1,859 @@
+use std::{ops::Range, time::Duration};
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+
+use crate::{
+    controller::ByteCount,
+    storage::{ByteStream, Entry, ObjectNotFound, ObjectWriter, Storage},
+    timestamp::Timestamp,
+    AppConfig,
+};
+
+/// How long signed requests are valid for. Requests are sent as soon as they are signed
+const SIGNATURE_DURATION: Duration = Duration::from_secs(60);
+
+/// Files are uploaded in parts of at least this size.
+/// S3 requires all parts apart from the last to be at least 5MiB
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Files are stored in an S3 compatible object store, such as AWS S3 or MinIO.
+/// Credentials are read from the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+/// environment variables
+#[derive(Clone)]
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Option<Credentials>,
+    client: reqwest::Client,
+}
+
+impl S3Storage {
+    pub fn new(config: &AppConfig) -> Result<Self> {
+        let endpoint = config
+            .s3_endpoint
+            .as_deref()
+            .context("An S3 endpoint must be set to use S3 storage")?;
+
+        let bucket_name = config
+            .s3_bucket
+            .clone()
+            .context("An S3 bucket must be set to use S3 storage")?;
+
+        let url_style = if config.s3_path_style {
+            UrlStyle::Path
+        } else {
+            UrlStyle::VirtualHost
+        };
+
+        let bucket = Bucket::new(
+            endpoint
+                .parse()
+                .with_context(|| format!("Bad S3 endpoint {endpoint}"))?,
+            url_style,
+            bucket_name,
+            config.s3_region.clone(),
+        )
+        .with_context(|| format!("Bad S3 endpoint {endpoint}"))?;
+
+        let credentials = Credentials::from_env();
+
+        if credentials.is_none() {
+            tracing::warn!("No S3 credentials are set. Requests will be anonymous");
+        }
+
+        Ok(Self {
+            bucket,
+            credentials,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let response = request.send().await.context("Failed to send S3 request")?;
+
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let body = response.text().await.unwrap_or_default();
+
+        let err = anyhow::anyhow!("S3 request failed with {status}: {body}");
+
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(err.context(ObjectNotFound));
+        }
+
+        Err(err)
+    }
+
+    /// Lists all objects beginning with `prefix`, following continuation tokens
+    async fn list_objects(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Result<Vec<rusty_s3::actions::ListObjectsV2Response>> {
+        let mut responses = Vec::new();
+        let mut continuation_token = None::<String>;
+
+        loop {
+            let mut action = self.bucket.list_objects_v2(self.credentials.as_ref());
+
+            action.with_prefix(prefix);
+
+            if let Some(delimiter) = delimiter {
+                action.with_delimiter(delimiter);
+            }
+
+            if let Some(continuation_token) = &continuation_token {
+                action.with_continuation_token(continuation_token.as_str());
+            }
+
+            let body = self
+                .send(self.client.get(action.sign(SIGNATURE_DURATION)))
+                .await
+                .with_context(|| format!("Failed to list {prefix}"))?
+                .text()
+                .await
+                .with_context(|| format!("Failed to list {prefix}"))?;
+
+            let response = rusty_s3::actions::ListObjectsV2::parse_response(&body)
+                .with_context(|| format!("Failed to parse listing of {prefix}"))?;
+
+            continuation_token = response.next_continuation_token.clone();
+
+            responses.push(response);
+
+            if continuation_token.is_none() {
+                return Ok(responses);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for S3Storage {
+    async fn read(&self, key: &str) -> Result<Vec<u8>> {
+        let url = self
+            .bucket
+            .get_object(self.credentials.as_ref(), key)
+            .sign(SIGNATURE_DURATION);
+
+        Ok(self
+            .send(self.client.get(url))
+            .await
+            .with_context(|| format!("Failed to read {key}"))?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read {key}"))?
+            .to_vec())
+    }
+
+    async fn write(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let url = self
+            .bucket
+            .put_object(self.credentials.as_ref(), key)
+            .sign(SIGNATURE_DURATION);
+
+        self.send(self.client.put(url).body(data))
+            .await
+            .with_context(|| format!("Failed to write {key}"))?;
+
+        Ok(())
+    }
+
+    async fn create(&self, key: &str) -> Result<Box<dyn ObjectWriter>> {
+        Ok(Box::new(S3Writer {
+            storage: self.clone(),
+            key: key.into(),
+            buffer: Vec::new(),
+            multipart_upload: None,
+        }))
+    }
+
+    async fn size(&self, key: &str) -> Result<ByteCount> {
+        let url = self
+            .bucket
+            .head_object(self.credentials.as_ref(), key)
+            .sign(SIGNATURE_DURATION);
+
+        let response = self
+            .send(self.client.head(url))
+            .await
+            .with_context(|| format!("Failed to get metadata for {key}"))?;
+
+        response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse().ok())
+            .map(ByteCount)
+            .with_context(|| format!("No size given for {key}"))
+    }
+
+    async fn open(&self, key: &str, range: Range<u64>) -> Result<ByteStream> {
+        if range.is_empty() {
+            return Ok(Box::pin(futures_util::stream::empty()));
+        }
+
+        let url = self
+            .bucket
+            .get_object(self.credentials.as_ref(), key)
+            .sign(SIGNATURE_DURATION);
+
+        let response = self
+            .send(self.client.get(url).header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            ))
+            .await
+            .with_context(|| format!("Failed to open {key}"))?;
+
+        // A server which ignores the range would send the file from the start
+        let status = response.status();
+
+        if status != reqwest::StatusCode::PARTIAL_CONTENT {
+            anyhow::bail!("Failed to open {key}: Expected a partial response, but got {status}");
+        }
+
+        Ok(Box::pin(
+            response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(std::io::Error::other)),
+        ))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let url = self
+            .bucket
+            .delete_object(self.credentials.as_ref(), key)
+            .sign(SIGNATURE_DURATION);
+
+        self.send(self.client.delete(url))
+            .await
+            .with_context(|| format!("Failed to remove {key}"))?;
+
+        Ok(())
+    }
+
+    async fn list(&self, directory: &str) -> Result<Vec<Entry>> {
+        let prefix = format!("{directory}/");
+
+        let mut entries = Vec::new();
+
+        for response in self.list_objects(&prefix, Some("/")).await? {
+            for common_prefix in response.common_prefixes {
+                let name = common_prefix
+                    .prefix
+                    .strip_prefix(&prefix)
+                    .unwrap_or(&common_prefix.prefix)
+                    .trim_end_matches('/');
+
+                entries.push(Entry::Directory { name: name.into() });
+            }
+
+            for object in response.contents {
+                let name = object.key.strip_prefix(&prefix).unwrap_or(&object.key);
+
+                entries.push(Entry::File {
+                    name: name.into(),
+                    size: ByteCount(object.size),
+                    modified: Timestamp::parse_rfc3339(&object.last_modified).ok(),
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    async fn usage(&self, directory: &str) -> Result<ByteCount> {
+        let mut size = ByteCount(0);
+
+        for response in self.list_objects(&format!("{directory}/"), None).await? {
+            for object in response.contents {
+                size += ByteCount(object.size);
+            }
+        }
+
+        Ok(size)
+    }
+
+    async fn available_space(&self) -> Result<Option<ByteCount>> {
+        Ok(None)
+    }
+
+    async fn create_directory(&self, _directory: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct MultipartUpload {
+    upload_id: String,
+    etags: Vec<String>,
+}
+
+/// Writes small files with a single request, and larger files using a multipart upload
+struct S3Writer {
+    storage: S3Storage,
+    key: String,
+    buffer: Vec<u8>,
+    multipart_upload: Option<MultipartUpload>,
+}
+
+impl S3Writer {
+    async fn upload_part(&mut self) -> Result<()> {
+        let storage = &self.storage;
+
+        let multipart_upload = match &mut self.multipart_upload {
+            Some(multipart_upload) => multipart_upload,
+            None => {
+                let url = storage
+                    .bucket
+                    .create_multipart_upload(storage.credentials.as_ref(), &self.key)
+                    .sign(SIGNATURE_DURATION);
+
+                let body = storage
+                    .send(storage.client.post(url))
+                    .await
+                    .with_context(|| format!("Failed to start upload of {}", self.key))?
+                    .text()
+                    .await
+                    .with_context(|| format!("Failed to start upload of {}", self.key))?;
+
+                let response = rusty_s3::actions::CreateMultipartUpload::parse_response(&body)
+                    .with_context(|| format!("Failed to start upload of {}", self.key))?;
+
+                self.multipart_upload.insert(MultipartUpload {
+                    upload_id: response.upload_id().into(),
+                    etags: Vec::new(),
+                })
+            }
+        };
+
+        let part_number = u16::try_from(multipart_upload.etags.len() + 1)
+            .with_context(|| format!("{} has too many parts", self.key))?;
+
+        let url = storage
+            .bucket
+            .upload_part(
+                storage.credentials.as_ref(),
+                &self.key,
+                part_number,
+                &multipart_upload.upload_id,
+            )
+            .sign(SIGNATURE_DURATION);
+
+        let response = storage
+            .send(
+                storage
+                    .client
+                    .put(url)
+                    .body(std::mem::take(&mut self.buffer)),
+            )
+            .await
+            .with_context(|| format!("Failed to upload part {part_number} of {}", self.key))?;
+
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .with_context(|| format!("No ETag given for part {part_number} of {}", self.key))?;
+
+        multipart_upload.etags.push(etag.into());
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl ObjectWriter for S3Writer {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.buffer.extend_from_slice(data);
+
+        if self.buffer.len() >= PART_SIZE {
+            self.upload_part().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<()> {
+        if self.multipart_upload.is_none() {
+            return self
+                .storage
+                .write(&self.key, std::mem::take(&mut self.buffer))
+                .await;
+        }
+
+        if !self.buffer.is_empty() {
+            self.upload_part().await?;
+        }
+
+        let storage = &self.storage;
+        let multipart_upload = self.multipart_upload.as_ref().unwrap();
+
+        let action = storage.bucket.complete_multipart_upload(
+            storage.credentials.as_ref(),
+            &self.key,
+            &multipart_upload.upload_id,
+            multipart_upload.etags.iter().map(String::as_str),
+        );
+
+        let url = action.sign(SIGNATURE_DURATION);
+
+        storage
+            .send(storage.client.post(url).body(action.body()))
+            .await
+            .with_context(|| format!("Failed to complete upload of {}", self.key))?;
+
+        // The upload is complete, so must not be aborted when dropped
+        self.multipart_upload = None;
+
+        Ok(())
+    }
+}
+
+impl Drop for S3Writer {
+    fn drop(&mut self) {
+        if let Some(MultipartUpload { upload_id, .. }) = self.multipart_upload.take() {
+            let storage = self.storage.clone();
+            let key = std::mem::take(&mut self.key);
+
+            tokio::spawn(async move {
+                let url = storage
+                    .bucket
+                    .abort_multipart_upload(storage.credentials.as_ref(), &key, &upload_id)
+                    .sign(SIGNATURE_DURATION);
+
+                if let Err(err) = storage.send(storage.client.delete(url)).await {
+                    tracing::error!("Failed to abort upload of {key}: {err:#}");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Bytes,
+        handler::Handler,
+        http::{header, HeaderMap, Method, StatusCode, Uri},
+        response::{IntoResponse, Response},
+        Extension, Router,
+    };
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    const BUCKET: &str = "files";
+
+    /// Listings are split into pages of this many entries, so that continuation tokens are used
+    const PAGE_SIZE: usize = 2;
+
+    /// Just enough of S3 to serve the requests made by `S3Storage`
+    #[derive(Default)]
+    struct FakeS3 {
+        objects: BTreeMap<String, Vec<u8>>,
+        /// Parts of incomplete multipart uploads, by upload ID
+        uploads: BTreeMap<String, BTreeMap<u16, Vec<u8>>>,
+        uploaded_parts: usize,
+        /// Whether to respond to ranged requests with the whole object, as some servers do
+        ignore_range: bool,
+    }
+
+    impl FakeS3 {
+        fn get(&self, key: &str, headers: &HeaderMap) -> Response {
+            let object = match self.objects.get(key) {
+                Some(object) => object,
+                None => return StatusCode::NOT_FOUND.into_response(),
+            };
+
+            let range = headers
+                .get(header::RANGE)
+                .and_then(|range| range.to_str().ok()?.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(start, end)| Some((start.parse().ok()?, end.parse::<usize>().ok()?)));
+
+            match range {
+                Some((start, end)) if !self.ignore_range => (
+                    StatusCode::PARTIAL_CONTENT,
+                    [(
+                        header::CONTENT_RANGE,
+                        format!("bytes {start}-{end}/{}", object.len()),
+                    )],
+                    object[start..=end].to_vec(),
+                )
+                    .into_response(),
+                _ => object.clone().into_response(),
+            }
+        }
+
+        fn list(&self, query: &BTreeMap<String, String>) -> Response {
+            let prefix = query.get("prefix").map_or("", String::as_str);
+            let delimiter = query.get("delimiter");
+
+            let mut entries = Vec::<(String, Option<usize>)>::new();
+
+            for (key, object) in &self.objects {
+                let rest = match key.strip_prefix(prefix) {
+                    Some(rest) => rest,
+                    None => continue,
+                };
+
+                match delimiter.and_then(|delimiter| rest.find(delimiter.as_str())) {
+                    Some(end) => {
+                        let common_prefix = format!("{prefix}{}/", &rest[..end]);
+
+                        if !entries.iter().any(|(name, _)| *name == common_prefix) {
+                            entries.push((common_prefix, None));
+                        }
+                    }
+                    None => entries.push((key.clone(), Some(object.len()))),
+                }
+            }
+
+            let start = query
+                .get("continuation-token")
+                .map_or(0, |token| token.parse().unwrap());
+
+            let mut body = format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><MaxKeys>{PAGE_SIZE}</MaxKeys>"#
+            );
+
+            for (name, size) in entries.iter().skip(start).take(PAGE_SIZE) {
+                match size {
+                    Some(size) => body.push_str(&format!(
+                        "<Contents><ETag>etag</ETag><Key>{name}</Key><LastModified>2024-01-02T03:04:05.000Z</LastModified><Size>{size}</Size></Contents>"
+                    )),
+                    None => body.push_str(&format!(
+                        "<CommonPrefixes><Prefix>{name}</Prefix></CommonPrefixes>"
+                    )),
+                }
+            }
+
+            if start + PAGE_SIZE < entries.len() {
+                body.push_str(&format!(
+                    "<NextContinuationToken>{}</NextContinuationToken>",
+                    start + PAGE_SIZE
+                ));
+            }
+
+            body.push_str("</ListBucketResult>");
+
+            body.into_response()
+        }
+
+        fn create_upload(&mut self) -> Response {
+            let upload_id = format!("upload{}", self.uploads.len() + 1);
+
+            self.uploads.insert(upload_id.clone(), BTreeMap::new());
+
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><InitiateMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>"#
+            )
+            .into_response()
+        }
+
+        fn upload_part(&mut self, upload_id: &str, part_number: u16, data: Bytes) -> Response {
+            match self.uploads.get_mut(upload_id) {
+                Some(parts) => {
+                    parts.insert(part_number, data.to_vec());
+                    self.uploaded_parts += 1;
+
+                    [(header::ETAG, format!("etag{part_number}"))].into_response()
+                }
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+
+        /// Joins the parts listed in the request, checking that their ETags match
+        fn complete_upload(&mut self, key: &str, upload_id: &str, body: &str) -> Response {
+            let mut parts = match self.uploads.remove(upload_id) {
+                Some(parts) => parts,
+                None => return StatusCode::NOT_FOUND.into_response(),
+            };
+
+            let mut object = Vec::new();
+
+            for part in body.split("<Part>").skip(1) {
+                let part_number = element(part, "PartNumber").parse::<u16>().unwrap();
+
+                if element(part, "ETag") != format!("etag{part_number}") {
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+
+                match parts.remove(&part_number) {
+                    Some(data) => object.extend(data),
+                    None => return StatusCode::BAD_REQUEST.into_response(),
+                }
+            }
+
+            self.objects.insert(key.into(), object);
+
+            StatusCode::OK.into_response()
+        }
+    }
+
+    fn element<'a>(xml: &'a str, name: &str) -> &'a str {
+        let start = xml.find(&format!("<{name}>")).unwrap() + name.len() + 2;
+        let end = xml.find(&format!("</{name}>")).unwrap();
+
+        &xml[start..end]
+    }
+
+    async fn handle(
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+        Extension(fake): Extension<Arc<Mutex<FakeS3>>>,
+    ) -> Response {
+        let mut fake = fake.lock().unwrap();
+
+        let query = uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| {
+                (
+                    name.to_owned(),
+                    percent_encoding::percent_decode_str(value)
+                        .decode_utf8_lossy()
+                        .into_owned(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let key = uri
+            .path()
+            .strip_prefix(&format!("/{BUCKET}"))
+            .unwrap_or_default()
+            .trim_start_matches('/');
+
+        match (method, query.get("uploadId")) {
+            (Method::GET, _) if key.is_empty() => fake.list(&query),
+            (Method::GET, _) => fake.get(key, &headers),
+            (Method::POST, _) if query.contains_key("uploads") => fake.create_upload(),
+            (Method::POST, Some(upload_id)) => {
+                fake.complete_upload(key, upload_id, &String::from_utf8_lossy(&body))
+            }
+            (Method::PUT, Some(upload_id)) => {
+                let part_number = query["partNumber"].parse().unwrap();
+
+                fake.upload_part(upload_id, part_number, body)
+            }
+            (Method::PUT, None) => {
+                fake.objects.insert(key.into(), body.to_vec());
+
+                StatusCode::OK.into_response()
+            }
+            (Method::DELETE, Some(upload_id)) => {
+                fake.uploads.remove(upload_id);
+
+                StatusCode::NO_CONTENT.into_response()
+            }
+            (Method::DELETE, None) => {
+                fake.objects.remove(key);
+
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    /// Starts a fake S3 server, returning storage which uses it
+    fn start() -> (S3Storage, Arc<Mutex<FakeS3>>) {
+        let fake = Arc::new(Mutex::new(FakeS3::default()));
+
+        let app = Router::new()
+            .fallback(handle.into_service())
+            .layer(Extension(fake.clone()));
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+
+        let endpoint = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let storage = S3Storage {
+            bucket: Bucket::new(
+                endpoint.parse().unwrap(),
+                UrlStyle::Path,
+                BUCKET.to_owned(),
+                "eu-west-2".to_owned(),
+            )
+            .unwrap(),
+            credentials: Some(Credentials::new("key", "secret")),
+            client: reqwest::Client::new(),
+        };
+
+        (storage, fake)
+    }
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn small_file_is_uploaded_in_one_request() {
+        let (storage, fake) = start();
+
+        let mut writer = storage.create("dir/small").await.unwrap();
+        writer.write(b"hello").await.unwrap();
+        writer.finish().await.unwrap();
+
+        let fake = fake.lock().unwrap();
+
+        assert_eq!(fake.objects["dir/small"], b"hello");
+        assert_eq!(fake.uploaded_parts, 0);
+    }
+
+    #[tokio::test]
+    async fn large_file_is_uploaded_in_parts() {
+        let (storage, fake) = start();
+
+        let data = test_data(PART_SIZE + 1000);
+
+        let mut writer = storage.create("dir/large").await.unwrap();
+
+        for chunk in data.chunks(1024 * 1024) {
+            writer.write(chunk).await.unwrap();
+        }
+
+        writer.finish().await.unwrap();
+
+        let fake = fake.lock().unwrap();
+
+        assert!(fake.objects["dir/large"] == data);
+        assert_eq!(fake.uploaded_parts, 2);
+        assert!(fake.uploads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unfinished_upload_is_aborted() {
+        let (storage, fake) = start();
+
+        let mut writer = storage.create("dir/unfinished").await.unwrap();
+        writer.write(&test_data(PART_SIZE)).await.unwrap();
+
+        assert_eq!(fake.lock().unwrap().uploads.len(), 1);
+
+        drop(writer);
+
+        // The upload is aborted in the background
+        for _ in 0..100 {
+            if fake.lock().unwrap().uploads.is_empty() {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let fake = fake.lock().unwrap();
+
+        assert!(fake.uploads.is_empty());
+        assert!(!fake.objects.contains_key("dir/unfinished"));
+    }
+
+    async fn read(storage: &S3Storage, range: Range<u64>) -> Result<Vec<u8>> {
+        Ok(storage
+            .open("dir/file", range)
+            .await?
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn open_reads_range() {
+        let (storage, fake) = start();
+
+        let data = test_data(100);
+
+        fake.lock()
+            .unwrap()
+            .objects
+            .insert("dir/file".into(), data.clone());
+
+        assert_eq!(read(&storage, 10..20).await.unwrap(), data[10..20]);
+        assert!(read(&storage, 20..20).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn open_rejects_ignored_range() {
+        let (storage, fake) = start();
+
+        {
+            let mut fake = fake.lock().unwrap();
+            fake.objects.insert("dir/file".into(), test_data(100));
+            fake.ignore_range = true;
+        }
+
+        assert!(read(&storage, 10..20).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn list_follows_continuation_tokens() {
+        let (storage, fake) = start();
+
+        {
+            let mut fake = fake.lock().unwrap();
+
+            for (key, size) in [
+                ("dir/a", 1),
+                ("dir/b", 2),
+                ("dir/c", 3),
+                ("dir/sub/d", 4),
+                ("dir/sub/e", 5),
+                ("dir/z", 6),
+                ("other/f", 7),
+            ] {
+                fake.objects.insert(key.into(), test_data(size));
+            }
+        }
+
+        let mut entries = storage
+            .list("dir")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| match entry {
+                Entry::File {
+                    name,
+                    size,
+                    modified,
+                } => {
+                    assert!(modified.is_some());
+
+                    (name, Some(size.0))
+                }
+                Entry::Directory { name } => (name, None),
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort();
+
+        assert_eq!(
+            entries,
+            [
+                ("a".to_owned(), Some(1)),
+                ("b".to_owned(), Some(2)),
+                ("c".to_owned(), Some(3)),
+                ("sub".to_owned(), None),
+                ("z".to_owned(), Some(6)),
+            ]
+        );
+
+        assert_eq!(storage.usage("dir").await.unwrap().0, 21);
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use futures_util::Stream;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{controller::ByteCount, timestamp::Timestamp, AppConfig};
+
+/// The size of the chunks in which local files are read
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+pub enum StorageBackend {
+    /// Files are stored in the local filesystem
+    Local,
+    /// Files are stored in an S3 compatible object store
+    S3,
+}
+
+/// Converts a relative path into a storage key, which always uses `/` as the separator
+pub fn path_key(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub fn join_key(directory: &str, name: &str) -> String {
+    format!("{directory}/{name}")
+}
+
+/// An object which doesn't exist in storage, as opposed to a failure to access storage
+#[derive(Debug)]
+pub struct ObjectNotFound;
+
+impl std::fmt::Display for ObjectNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        "Object not found".fmt(f)
+    }
+}
+
+impl std::error::Error for ObjectNotFound {}
+
+pub enum Entry {
+    File {
+        name: String,
+        size: ByteCount,
+        /// When the file was last written, if the storage records it
+        modified: Option<Timestamp>,
+    },
+    Directory {
+        name: String,
+    },
+}
+
+/// A file being written to storage. If dropped before being finished, the file is discarded
+#[async_trait::async_trait]
+pub trait ObjectWriter: Send {
+    async fn write(&mut self, data: &[u8]) -> Result<()>;
+
+    async fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Where token configs and files are stored. Files are identified by keys, which are
+/// `/` separated paths relative to the root of the storage
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    /// Reads a small file, such as a token config, in its entirety
+    async fn read(&self, key: &str) -> Result<Vec<u8>>;
+
+    /// Replaces the contents of a small file, such as a token config
+    async fn write(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Creates a file whose contents are written in chunks
+    async fn create(&self, key: &str) -> Result<Box<dyn ObjectWriter>>;
+
+    async fn size(&self, key: &str) -> Result<ByteCount>;
+
+    /// Reads part of a file
+    async fn open(&self, key: &str, range: Range<u64>) -> Result<ByteStream>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Lists the files and directories directly within `directory`.
+    /// A directory which doesn't exist is treated as empty
+    async fn list(&self, directory: &str) -> Result<Vec<Entry>>;
+
+    /// The total size of all files within `directory` and its subdirectories
+    async fn usage(&self, directory: &str) -> Result<ByteCount>;
+
+    /// The space available for new files, or `None` if it is unlimited
+    async fn available_space(&self) -> Result<Option<ByteCount>>;
+
+    /// Creates a directory and its parents. Storage without real directories may do nothing
+    async fn create_directory(&self, directory: &str) -> Result<()>;
+}
+
+pub fn new(config: &AppConfig) -> Result<Arc<dyn Storage>> {
+    Ok(match config.storage {
+        StorageBackend::Local => Arc::new(LocalStorage {
+            root: config.files.clone(),
+        }),
+        StorageBackend::S3 => Arc::new(crate::s3::S3Storage::new(config)?),
+    })
+}
+
+fn directory_size(directory: &Path) -> Result<ByteCount> {
+    let mut size = ByteCount(0);
+
+    for entry in std::fs::read_dir(directory)
+        .with_context(|| format!("Failed to read directory {}", directory.display()))?
+    {
+        let entry =
+            entry.with_context(|| format!("Failed to read entry in {}", directory.display()))?;
+
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("Failed to read metadata for {}", entry.path().display()))?;
+
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += ByteCount(metadata.len());
+        }
+    }
+
+    Ok(size)
+}
+
+/// Reads part of a file in the local filesystem
+pub async fn open_local(path: &Path, range: Range<u64>) -> Result<ByteStream> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    file.seek(std::io::SeekFrom::Start(range.start))
+        .await
+        .with_context(|| format!("Failed to seek in {}", path.display()))?;
+
+    Ok(Box::pin(tokio_util::io::ReaderStream::with_capacity(
+        file.take(range.end.saturating_sub(range.start)),
+        READ_CHUNK_SIZE,
+    )))
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for LocalStorage {
+    async fn read(&self, key: &str) -> Result<Vec<u8>> {
+        let path = self.path(key);
+
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))
+    }
+
+    async fn write(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key);
+
+        tokio::fs::write(&path, data)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    async fn create(&self, key: &str) -> Result<Box<dyn ObjectWriter>> {
+        let path = self.path(key);
+
+        let file = tokio::fs::File::create(&path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        Ok(Box::new(LocalWriter {
+            path,
+            file: Some(file),
+        }))
+    }
+
+    async fn size(&self, key: &str) -> Result<ByteCount> {
+        let path = self.path(key);
+
+        let metadata = tokio::fs::metadata(&path).await.map_err(|err| {
+            let err = if err.kind() == std::io::ErrorKind::NotFound {
+                anyhow::Error::new(ObjectNotFound)
+            } else {
+                anyhow::Error::new(err)
+            };
+
+            err.context(format!("Failed to get metadata for {}", path.display()))
+        })?;
+
+        Ok(ByteCount(metadata.len()))
+    }
+
+    async fn open(&self, key: &str, range: Range<u64>) -> Result<ByteStream> {
+        open_local(&self.path(key), range).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key);
+
+        tokio::fs::remove_file(&path)
+            .await
+            .with_context(|| format!("Failed to remove {}", path.display()))
+    }
+
+    async fn list(&self, directory: &str) -> Result<Vec<Entry>> {
+        let directory = self.path(directory);
+
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read directory {}", directory.display()))
+            }
+        };
+
+        entries
+            .map(|entry| {
+                let entry = entry
+                    .with_context(|| format!("Failed to read entry in {}", directory.display()))?;
+
+                let name = entry.file_name().to_string_lossy().into_owned();
+
+                let metadata = entry.metadata().with_context(|| {
+                    format!("Failed to read metadata for {}", entry.path().display())
+                })?;
+
+                Ok(if metadata.is_dir() {
+                    Entry::Directory { name }
+                } else {
+                    Entry::File {
+                        name,
+                        size: ByteCount(metadata.len()),
+                        modified: metadata.modified().ok().map(Timestamp::from),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    async fn usage(&self, directory: &str) -> Result<ByteCount> {
+        let directory = self.path(directory);
+
+        // Walking a large directory may take a while, so mustn't block other requests
+        tokio::task::spawn_blocking(move || directory_size(&directory))
+            .await
+            .context("Failed to find directory size")?
+    }
+
+    async fn available_space(&self) -> Result<Option<ByteCount>> {
+        Ok(Some(ByteCount(
+            fs2::available_space(&self.root).with_context(|| {
+                format!("Failed to get available space for {}", self.root.display())
+            })?,
+        )))
+    }
+
+    async fn create_directory(&self, directory: &str) -> Result<()> {
+        let path = self.path(directory);
+
+        tokio::fs::create_dir_all(&path)
+            .await
+            .with_context(|| format!("Failed to create directory {}", path.display()))
+    }
+}
+
+struct LocalWriter {
+    path: PathBuf,
+    file: Option<tokio::fs::File>,
+}
+
+#[async_trait::async_trait]
+impl ObjectWriter for LocalWriter {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file
+            .as_mut()
+            .unwrap()
+            .write_all(data)
+            .await
+            .with_context(|| format!("Failed to write to {}", self.path.display()))
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file
+            .as_mut()
+            .unwrap()
+            .flush()
+            .await
+            .with_context(|| format!("Failed to flush {}", self.path.display()))?;
+
+        self.file.take();
+
+        Ok(())
+    }
+}
+
+impl Drop for LocalWriter {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            if let Err(err) = std::fs::remove_file(&self.path) {
+                tracing::error!("Failed to remove {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
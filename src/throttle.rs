@@ -0,0 +1,81 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::controller::ByteCount;
+
+struct BucketState {
+    /// May be negative, in which case the bucket is in debt and takers must wait for it to refill
+    available: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket, holding up to one second's worth of bytes.
+/// Waiting takers are served in order, so the bandwidth is shared fairly between them
+pub struct TokenBucket {
+    rate: f64,
+    state: tokio::sync::Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// `rate` is in bytes per second
+    pub fn new(ByteCount(rate): ByteCount) -> Self {
+        let rate = rate.max(1) as f64;
+
+        Self {
+            rate,
+            state: tokio::sync::Mutex::new(BucketState {
+                available: rate,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> ByteCount {
+        ByteCount(self.rate as u64)
+    }
+
+    async fn take(&self, ByteCount(amount): ByteCount) {
+        let mut state = self.state.lock().await;
+
+        let now = Instant::now();
+
+        state.available = (state.available
+            + now.duration_since(state.last_refill).as_secs_f64() * self.rate)
+            .min(self.rate);
+        state.last_refill = now;
+
+        state.available -= amount as f64;
+
+        if state.available < 0.0 {
+            // Hold the lock while waiting, so that other takers queue up behind us
+            tokio::time::sleep(Duration::from_secs_f64(-state.available / self.rate)).await;
+        }
+    }
+}
+
+/// The set of bandwidth limits which apply to a single download
+#[derive(Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+    pub fn with_bucket(mut self, bucket: Arc<TokenBucket>) -> Self {
+        self.buckets.push(bucket);
+
+        self
+    }
+
+    pub fn with_rate(self, rate: ByteCount) -> Self {
+        self.with_bucket(Arc::new(TokenBucket::new(rate)))
+    }
+
+    /// Waits until `amount` bytes may be sent without exceeding any of the limits
+    pub async fn take(&self, amount: ByteCount) {
+        for bucket in &self.buckets {
+            bucket.take(amount).await;
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use image::{ImageFormat, Rgb, RgbImage};
+
+use crate::controller::ByteCount;
+
+/// The maximum width and height of thumbnails, in pixels
+const THUMBNAIL_SIZE: u32 = 256;
+
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Larger images are not decoded, to limit the memory used to generate thumbnails
+pub const MAX_IMAGE_SIZE: ByteCount = ByteCount(64 * 1024 * 1024);
+
+/// Thumbnails are always JPEGs, regardless of the format of the image
+pub const THUMBNAIL_EXTENSION: &str = "jpg";
+
+/// Whether a thumbnail can be generated for the file, judging by its extension
+pub fn is_supported(filename: &Path) -> bool {
+    matches!(
+        ImageFormat::from_path(filename),
+        Ok(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP)
+    )
+}
+
+/// Generates a thumbnail of an image. This is CPU intensive, so shouldn't be called from
+/// an async task
+pub fn generate(image: &[u8]) -> Result<Vec<u8>> {
+    let thumbnail = image::load_from_memory(image)
+        .context("Failed to decode image")?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .into_rgba8();
+
+    // JPEG has no transparency, so transparent pixels are shown against white
+    let flattened = RgbImage::from_fn(thumbnail.width(), thumbnail.height(), |x, y| {
+        let [r, g, b, a] = thumbnail.get_pixel(x, y).0;
+
+        let blend =
+            |channel: u8| ((channel as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+
+        Rgb([blend(r), blend(g), blend(b)])
+    });
+
+    let mut thumbnail = Vec::new();
+
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut thumbnail, THUMBNAIL_QUALITY)
+        .encode_image(&flattened)
+        .context("Failed to encode thumbnail")?;
+
+    Ok(thumbnail)
+}
@@ -23,6 +23,58 @@ impl Timestamp {
     pub fn into_filename(self) -> FilenameTimestamp {
         FilenameTimestamp(self.0)
     }
+
+    /// Parses a timestamp such as `2022-05-01T12:00:00.000Z`, as used by object stores
+    pub fn parse_rfc3339(timestamp: &str) -> Result<Self, time::error::Parse> {
+        time::OffsetDateTime::parse(timestamp, &time::format_description::well_known::Rfc3339)
+            .map(Self::local)
+    }
+
+    /// Parses the start of a filename made by `into_filename`, in the server's timezone
+    pub fn parse_filename(filename: &str) -> Option<Self> {
+        let format = time::format_description::parse("[year][month][day][hour][minute]").ok()?;
+
+        let datetime = time::PrimitiveDateTime::parse(filename.get(..12)?, &format).ok()?;
+
+        Some(Self(datetime.assume_offset(
+            time::UtcOffset::current_local_offset().unwrap_or(time::UtcOffset::UTC),
+        )))
+    }
+
+    pub fn date(self) -> WebDate {
+        WebDate(self.0.date())
+    }
+
+    pub fn to_rfc3339(self) -> Result<String, time::error::Format> {
+        self.0
+            .format(&time::format_description::well_known::Rfc3339)
+    }
+
+    /// Shows the time in the server's timezone, if it is known
+    fn local(timestamp: time::OffsetDateTime) -> Self {
+        Self(match time::UtcOffset::current_local_offset() {
+            Ok(offset) => timestamp.to_offset(offset),
+            Err(_) => timestamp,
+        })
+    }
+}
+
+impl From<std::time::SystemTime> for Timestamp {
+    fn from(timestamp: std::time::SystemTime) -> Self {
+        Self::local(timestamp.into())
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let year = self.0.year();
+        let month = self.0.month() as u8;
+        let day = self.0.day();
+        let hour = self.0.hour();
+        let minute = self.0.minute();
+
+        write!(f, "{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}")
+    }
 }
 
 impl std::ops::Add<time::Duration> for Timestamp {
@@ -35,6 +87,14 @@ impl std::ops::Add<time::Duration> for Timestamp {
     }
 }
 
+impl std::ops::Sub for Timestamp {
+    type Output = time::Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for Timestamp {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -141,12 +201,6 @@ impl serde::Serialize for Timestamp {
 #[derive(Debug, Clone, Copy)]
 pub struct WebTimestamp(time::OffsetDateTime);
 
-impl WebTimestamp {
-    pub fn now() -> Result<Self, time::error::IndeterminateOffset> {
-        time::OffsetDateTime::now_local().map(Self)
-    }
-}
-
 impl From<Timestamp> for WebTimestamp {
     fn from(Timestamp(timestamp): Timestamp) -> Self {
         Self(timestamp)
@@ -204,3 +258,29 @@ impl std::ops::Add<time::Duration> for WebTimestamp {
         self
     }
 }
+
+/// A date as used by `<input type="date">`, e.g. `2022-05-01`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WebDate(time::Date);
+
+impl std::fmt::Display for WebDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let year = self.0.year();
+        let month = self.0.month() as u8;
+        let day = self.0.day();
+
+        write!(f, "{year:04}-{month:02}-{day:02}")
+    }
+}
+
+impl std::str::FromStr for WebDate {
+    type Err = time::error::Parse;
+
+    fn from_str(date: &str) -> Result<Self, Self::Err> {
+        time::Date::parse(
+            date,
+            &time::format_description::well_known::Iso8601::PARSING,
+        )
+        .map(Self)
+    }
+}
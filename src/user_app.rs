@@ -4,14 +4,71 @@ use std::{
 };
 
 use askama_axum::IntoResponse as _;
-use axum::{extract::Multipart, http::StatusCode, response::IntoResponse, Router, TypedHeader};
+use axum::{
+    extract::Multipart,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json, Router, TypedHeader,
+};
 use axum_extra::routing::RouterExt;
+use futures_util::StreamExt;
 
-use crate::controller::User;
+use crate::{
+    content::{self, accepts_json, ListingFormat},
+    controller::{
+        ByteCount, DownloadCompletion, Exchange, FileExists, FilePreview, QuotaExceeded,
+        RangeNotSatisfiable, ShareDirectoryListing, SharedFile, SortKey, SortOrder, TokenNotFound,
+        UnsupportedFile, UploadRules, User,
+    },
+    health, logging,
+    metrics::{self, QuotaRejection, Transfer},
+    preview::{self, PreviewKind},
+    rate_limit::{self, RateLimiter},
+    storage::{ByteStream, ObjectNotFound},
+    throttle::Throttle,
+    AppConfig,
+};
 
 #[derive(askama::Template)]
 #[template(path = "user_upload.html")]
-struct UploadFiles {}
+struct UploadFiles {
+    rules: UploadRules,
+}
+
+impl UploadFiles {
+    fn accepted_files(&self) -> String {
+        accepted_files(&self.rules)
+    }
+
+    fn max_filesize_mib(&self) -> Option<f64> {
+        max_filesize_mib(&self.rules)
+    }
+}
+
+/// The allowed types, in the format expected by Dropzone's `acceptedFiles` option
+fn accepted_files(rules: &UploadRules) -> String {
+    rules
+        .restrictions
+        .allowed_types
+        .iter()
+        .map(|allowed_type| {
+            if allowed_type.contains('/') || allowed_type.starts_with('.') {
+                allowed_type.clone()
+            } else {
+                format!(".{allowed_type}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The maximum file size in MiB, as expected by Dropzone's `maxFilesize` option
+fn max_filesize_mib(rules: &UploadRules) -> Option<f64> {
+    rules
+        .restrictions
+        .max_file_size
+        .map(|max_file_size| max_file_size.0 as f64 / (1024.0 * 1024.0))
+}
 
 #[derive(axum_extra::routing::TypedPath, serde::Deserialize)]
 #[typed_path("/upload/:token")]
@@ -19,22 +76,92 @@ struct UploadTokenPath {
     token: crate::controller::Token,
 }
 
-async fn upload_files_page(_: UploadTokenPath) -> impl IntoResponse {
-    UploadFiles {}.into_response()
+async fn upload_files_page(
+    UploadTokenPath { token }: UploadTokenPath,
+    user: axum::Extension<User>,
+) -> Result<impl IntoResponse, StatusCode> {
+    user.upload_rules(&token)
+        .await
+        .map(|rules| UploadFiles { rules }.into_response())
+        .map_err(|err| {
+            tracing::error!("{:#}", err);
+
+            user.metrics().failed_token_lookup();
+
+            StatusCode::NOT_FOUND
+        })
 }
 
 async fn upload_files(
     UploadTokenPath { token }: UploadTokenPath,
-    TypedHeader(content_length): TypedHeader<axum::headers::ContentLength>,
+    content_length: Option<TypedHeader<axum::headers::ContentLength>>,
     files: Multipart,
     user: axum::Extension<User>,
 ) -> impl IntoResponse {
-    user.upload_files(token, content_length.0, files)
+    let content_length =
+        content_length.map(|TypedHeader(axum::headers::ContentLength(length))| length);
+
+    user.upload_files(token, content_length, files)
         .await
         .map(|()| "SUCCESS")
         .map_err(|err| {
             tracing::error!("Failed to upload files: {err:#}");
-            StatusCode::INTERNAL_SERVER_ERROR
+
+            if let Some(QuotaExceeded { rejection, message }) = err.downcast_ref() {
+                let status = match rejection {
+                    QuotaRejection::FileType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    _ => StatusCode::PAYLOAD_TOO_LARGE,
+                };
+
+                IntoResponse::into_response((status, message.clone()))
+            } else if let Some(file_exists) = err.downcast_ref::<FileExists>() {
+                IntoResponse::into_response((StatusCode::CONFLICT, file_exists.to_string()))
+            } else if err.is::<TokenNotFound>() {
+                user.metrics().failed_token_lookup();
+
+                IntoResponse::into_response(StatusCode::NOT_FOUND)
+            } else {
+                IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        })
+}
+
+#[derive(askama::Template)]
+#[template(path = "user_exchange.html")]
+struct ExchangePage {
+    exchange: Exchange,
+}
+
+impl ExchangePage {
+    fn accepted_files(&self) -> String {
+        accepted_files(&self.exchange.rules)
+    }
+
+    fn max_filesize_mib(&self) -> Option<f64> {
+        max_filesize_mib(&self.exchange.rules)
+    }
+}
+
+#[derive(axum_extra::routing::TypedPath, serde::Deserialize)]
+#[typed_path("/exchange/:token")]
+struct ExchangePath {
+    token: crate::controller::Token,
+}
+
+/// A single page to download the files provided by an exchange, and to upload replies
+async fn exchange_page(
+    ExchangePath { token }: ExchangePath,
+    user: axum::Extension<User>,
+) -> Result<impl IntoResponse, StatusCode> {
+    user.exchange(&token)
+        .await
+        .map(|exchange| ExchangePage { exchange }.into_response())
+        .map_err(|err| {
+            tracing::error!("{:#}", err);
+
+            user.metrics().failed_token_lookup();
+
+            StatusCode::NOT_FOUND
         })
 }
 
@@ -44,20 +171,218 @@ struct DirectoryListingPath {
     token: crate::controller::Token,
 }
 
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListingView {
+    #[default]
+    Table,
+    Gallery,
+}
+
+#[derive(serde::Deserialize)]
+struct ListingOptions {
+    #[serde(default)]
+    view: ListingView,
+    /// Overrides the format requested with the `Accept` header
+    format: Option<ListingFormat>,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+/// A share's files, for scripts which download them
+#[derive(serde::Serialize)]
+struct JsonListing {
+    name: String,
+    end_to_end: bool,
+    file_count: usize,
+    total_size: ByteCount,
+    files: Vec<JsonFile>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonFile {
+    name: String,
+    size: ByteCount,
+    modified: Option<String>,
+    mime: String,
+    sha256: Option<String>,
+    url: String,
+}
+
+impl JsonListing {
+    fn new(listing: ShareDirectoryListing, config: &AppConfig) -> Self {
+        let total_size = listing.total_size();
+
+        let ShareDirectoryListing {
+            token,
+            name,
+            end_to_end,
+            files,
+            ..
+        } = listing;
+
+        Self {
+            name,
+            end_to_end,
+            file_count: files.len(),
+            total_size,
+            files: files
+                .into_iter()
+                .map(|file| JsonFile {
+                    url: config.file_url(&token, &file.name),
+                    modified: file
+                        .modified
+                        .and_then(|modified| modified.to_rfc3339().ok()),
+                    mime: file.mime.to_string(),
+                    name: file.name,
+                    size: file.size,
+                    sha256: file.sha256,
+                })
+                .collect(),
+        }
+    }
+}
+
 async fn directory_listing(
     DirectoryListingPath { token }: DirectoryListingPath,
+    axum::extract::Query(ListingOptions {
+        view,
+        format,
+        sort,
+        order,
+    }): axum::extract::Query<ListingOptions>,
+    headers: axum::http::HeaderMap,
     user: axum::Extension<User>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    user.directory_listing(token)
+    let format = format.unwrap_or(if accepts_json(&headers) {
+        ListingFormat::Json
+    } else {
+        ListingFormat::Html
+    });
+
+    let single_file = user.single_file(&token).await.map_err(|err| {
+        tracing::error!("{:#}", err);
+
+        user.metrics().failed_token_lookup();
+
+        StatusCode::NOT_FOUND
+    })?;
+
+    // The links of single file shares lead straight to the file
+    if let Some(filename) = single_file {
+        if format == ListingFormat::Json {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        let path = content::encode_path_segment(&filename);
+
+        let location = match preview::kind(std::path::Path::new(&filename)) {
+            Some(_) => format!("../../preview/{token}/{path}"),
+            None => path,
+        };
+
+        return Ok(axum::response::Redirect::temporary(&location).into_response());
+    }
+
+    user.directory_listing(token, matches!(view, ListingView::Gallery), sort, order)
         .await
-        .map(|listing| listing.into_response())
+        .map(|listing| {
+            let vary = [(axum::http::header::VARY, "accept")];
+
+            match format {
+                ListingFormat::Html => (vary, listing.into_response()).into_response(),
+                ListingFormat::Json => {
+                    (vary, Json(JsonListing::new(listing, user.config()))).into_response()
+                }
+            }
+        })
         .map_err(|err| {
             tracing::error!("{:#}", err);
 
+            user.metrics().failed_token_lookup();
+
             StatusCode::NOT_FOUND
         })
 }
 
+#[derive(askama::Template)]
+#[template(path = "user_preview.html")]
+struct Preview {
+    preview: FilePreview,
+}
+
+impl Preview {
+    fn language(&self) -> String {
+        preview::language(self.preview.filename.as_ref())
+    }
+}
+
+#[derive(axum_extra::routing::TypedPath, serde::Deserialize)]
+#[typed_path("/preview/:token/:filename")]
+struct PreviewPath {
+    token: crate::controller::Token,
+    filename: crate::controller::Filename,
+}
+
+async fn preview(
+    PreviewPath { token, filename }: PreviewPath,
+    user: axum::Extension<User>,
+) -> Result<impl IntoResponse, StatusCode> {
+    user.preview(token, filename)
+        .await
+        .map(|preview| Preview { preview }.into_response())
+        .map_err(|err| {
+            tracing::error!("Could not preview file: {err:#}");
+
+            file_error_status(&user, &err)
+        })
+}
+
+#[derive(axum_extra::routing::TypedPath, serde::Deserialize)]
+#[typed_path("/thumbnail/:token/:filename")]
+struct ThumbnailPath {
+    token: crate::controller::Token,
+    filename: crate::controller::Filename,
+}
+
+async fn thumbnail(
+    ThumbnailPath { token, filename }: ThumbnailPath,
+    user: axum::Extension<User>,
+) -> Result<impl IntoResponse, StatusCode> {
+    user.thumbnail(token, filename)
+        .await
+        .map(|thumbnail| {
+            (
+                [
+                    (axum::http::header::CONTENT_TYPE, "image/jpeg"),
+                    (axum::http::header::CACHE_CONTROL, "private, max-age=3600"),
+                ],
+                thumbnail,
+            )
+        })
+        .map_err(|err| {
+            tracing::error!("Could not get thumbnail: {err:#}");
+
+            file_error_status(&user, &err)
+        })
+}
+
+/// Only a token or file which doesn't exist counts as a failed lookup, so that files which
+/// can't be previewed or storage failures don't get users banned
+fn file_error_status(user: &User, err: &anyhow::Error) -> StatusCode {
+    if err.is::<TokenNotFound>() || err.is::<ObjectNotFound>() {
+        user.metrics().failed_token_lookup();
+
+        StatusCode::NOT_FOUND
+    } else if err.is::<UnsupportedFile>() {
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 #[derive(axum_extra::routing::TypedPath, serde::Deserialize)]
 #[typed_path("/share/:token/:filename")]
 struct SharedFilePath {
@@ -65,41 +390,160 @@ struct SharedFilePath {
     filename: crate::controller::Filename,
 }
 
+/// The state of a shared file being streamed to a client
+struct Download {
+    stream: ByteStream,
+    throttle: Throttle,
+    remaining: u64,
+    completion: Option<DownloadCompletion>,
+    transfer: Transfer,
+}
+
+impl Download {
+    async fn next_chunk(mut self) -> std::io::Result<Option<(axum::body::Bytes, Self)>> {
+        let data = match self.stream.next().await {
+            Some(data) => data?,
+            None => return Ok(None),
+        };
+
+        let size = ByteCount(data.len() as u64);
+
+        self.throttle.take(size).await;
+        self.transfer.downloaded(size);
+
+        // The stream isn't polled again once Content-Length bytes have been sent,
+        // so completion is detected by counting bytes rather than waiting for the end of the file
+        self.remaining = self.remaining.saturating_sub(size.0);
+
+        if self.remaining == 0 {
+            if let Some(completion) = self.completion.take() {
+                completion.complete();
+            }
+        }
+
+        Ok(Some((data, self)))
+    }
+}
+
 async fn share_file(
     SharedFilePath { token, filename }: SharedFilePath,
+    range: Option<TypedHeader<axum::headers::Range>>,
+    host: Option<TypedHeader<axum::headers::Host>>,
     user: axum::Extension<User>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let (file, metadata, mime) = user
-        .open_shared_file(token, filename)
+) -> Result<Response, Response> {
+    let name = filename.to_string();
+
+    // If there is a separate content origin, files are only served from it
+    if let Some(content_host) = user.config().content_host() {
+        let is_content_host = host
+            .is_some_and(|TypedHeader(host)| host.to_string().eq_ignore_ascii_case(&content_host));
+
+        if !is_content_host {
+            if let Some(content_url) = user.config().content_url(&token, &name) {
+                return Ok(axum::response::Redirect::temporary(&content_url).into_response());
+            }
+        }
+    }
+
+    // Requests for multiple ranges are answered with the whole file
+    let range = range.and_then(|TypedHeader(range)| {
+        let mut ranges = range.iter();
+
+        match (ranges.next(), ranges.next()) {
+            (Some(range), None) => Some(range),
+            _ => None,
+        }
+    });
+
+    let SharedFile {
+        stream,
+        size,
+        range,
+        mime,
+        throttle,
+        mut completion,
+        transfer,
+    } = user
+        .open_shared_file(token, filename, range)
         .await
         .map_err(|err| {
+            if let Some(RangeNotSatisfiable { size }) = err.downcast_ref() {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    TypedHeader(axum::headers::ContentRange::unsatisfied_bytes(size.0)),
+                )
+                    .into_response();
+            }
+
             tracing::error!("Could not open shared file: {:#}", err);
 
-            StatusCode::NOT_FOUND
+            user.metrics().failed_token_lookup();
+
+            IntoResponse::into_response(StatusCode::NOT_FOUND)
         })?;
 
-    let stream = futures_util::stream::try_unfold(
-        tokio::io::BufReader::new(file),
-        |mut reader| async move {
-            use tokio::io::AsyncBufReadExt;
+    let length = range
+        .as_ref()
+        .map_or(size.0, |range| range.end - range.start);
 
-            let data = reader.fill_buf().await?;
-            let data = axum::body::Bytes::copy_from_slice(data);
-            reader.consume(data.len());
+    if length == 0 {
+        if let Some(completion) = completion.take() {
+            completion.complete();
+        }
+    }
 
-            Ok::<_, tokio::io::Error>((!data.is_empty()).then(|| (data, reader)))
+    let body = axum::body::StreamBody::new(futures_util::stream::try_unfold(
+        Download {
+            stream,
+            throttle,
+            remaining: length,
+            completion,
+            transfer,
         },
+        Download::next_chunk,
+    ));
+
+    let headers = (
+        content::headers(&name, &mime, false),
+        TypedHeader(axum::headers::ContentType::from(mime)),
+        TypedHeader(axum::headers::ContentLength(length)),
+        TypedHeader(axum::headers::AcceptRanges::bytes()),
     );
 
-    let body = axum::body::StreamBody::new(stream);
+    let mut response = match range {
+        Some(range) => {
+            let content_range =
+                axum::headers::ContentRange::bytes(range, size.0).map_err(|err| {
+                    tracing::error!("Bad content range: {err}");
+
+                    IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR)
+                })?;
+
+            (
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                TypedHeader(content_range),
+                body,
+            )
+                .into_response()
+        }
+        None => (StatusCode::OK, headers, body).into_response(),
+    };
+
+    // End-to-end encrypted files are fetched by scripts on the user app's pages
+    if user.config().content_host().is_some() {
+        if let Some(origin) = user
+            .config()
+            .user_origin()
+            .and_then(|origin| axum::http::HeaderValue::from_str(&origin).ok())
+        {
+            response
+                .headers_mut()
+                .insert(axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+    }
 
-    Ok((
-        StatusCode::OK,
-        axum::TypedHeader(axum::headers::ContentType::from(mime)),
-        axum::TypedHeader(axum::headers::ContentLength(metadata.len())),
-        body,
-    )
-        .into_response())
+    Ok(response)
 }
 
 pub async fn run(user: User, shutdown_signal: impl Future<Output = ()>) {
@@ -115,14 +559,30 @@ pub async fn run(user: User, shutdown_signal: impl Future<Output = ()>) {
     let app = Router::new()
         .typed_get(upload_files_page)
         .typed_post(upload_files)
+        .typed_get(exchange_page)
         .typed_get(share_file)
         .typed_get(directory_listing)
-        .layer(axum::Extension(user));
+        .typed_get(thumbnail)
+        .typed_get(preview)
+        .layer(axum::middleware::from_fn(rate_limit::limit_failed_lookups))
+        // Merged after the rate limiter, so that health checks are never banned
+        .merge(health::public_routes(user.config(), user.storage().clone()))
+        .layer(axum::middleware::from_fn({
+            let metrics = user.metrics().clone();
+            move |request, next| metrics::track_requests(metrics.clone(), "user", request, next)
+        }))
+        .layer(axum::Extension(RateLimiter::new(
+            &user.config().trusted_proxies,
+        )))
+        .layer(axum::Extension(user))
+        .layer(axum::middleware::from_fn(|request, next| {
+            logging::request_span("user", request, next)
+        }));
 
     tracing::info!("User App is listening on {addr}");
 
     match axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal)
         .await
     {
@@ -0,0 +1,545 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::events::Event;
+
+const DELIVERY_EXTENSION: &str = "json";
+
+const MAXIMUM_ATTEMPTS: u32 = 10;
+
+/// The delay before the first retry. Each subsequent retry doubles the delay
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+const MAXIMUM_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const EVENT_HEADER: &str = "X-File-Sharer-Event";
+const DELIVERY_HEADER: &str = "X-File-Sharer-Delivery";
+const SIGNATURE_HEADER: &str = "X-File-Sharer-Signature";
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// If set, the payload is signed with HMAC-SHA256 using this secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl Webhook {
+    fn signature(&self, payload: &str) -> Option<String> {
+        use hmac::Mac;
+
+        let mut mac =
+            hmac::Hmac::<sha2::Sha256>::new_from_slice(self.secret.as_ref()?.as_bytes()).ok()?;
+
+        mac.update(payload.as_bytes());
+
+        Some(format!(
+            "sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    id: &'a str,
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// A pending delivery of an event to a webhook, persisted so that it survives restarts.
+/// The payload is signed as it is queued, so that the webhook's secret isn't persisted
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Delivery {
+    id: String,
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    event: String,
+    payload: String,
+    attempts: u32,
+    /// Seconds since the Unix epoch
+    next_attempt: u64,
+}
+
+impl Delivery {
+    async fn attempt(&self, client: &reqwest::Client) -> Result<()> {
+        let mut request = client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, &self.event)
+            .header(DELIVERY_HEADER, &self.id)
+            .body(self.payload.clone());
+
+        if let Some(signature) = &self.signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        request
+            .send()
+            .await
+            .with_context(|| format!("Failed to send webhook to {}", self.url))?
+            .error_for_status()
+            .with_context(|| format!("Webhook {} rejected delivery", self.url))?;
+
+        Ok(())
+    }
+
+    fn retry_delay(&self) -> Duration {
+        INITIAL_RETRY_DELAY
+            .checked_mul(2_u32.saturating_pow(self.attempts.saturating_sub(1)))
+            .map_or(MAXIMUM_RETRY_DELAY, |delay| delay.min(MAXIMUM_RETRY_DELAY))
+    }
+}
+
+/// Queues events for delivery to webhooks, retrying failed deliveries with exponential backoff
+#[derive(Clone)]
+pub struct Webhooks {
+    queue_directory: PathBuf,
+    global_webhooks: Arc<[Webhook]>,
+    wake: Arc<tokio::sync::Notify>,
+}
+
+impl Webhooks {
+    pub fn new(queue_directory: PathBuf, global_webhooks: Vec<Webhook>) -> Result<Self> {
+        std::fs::create_dir_all(&queue_directory)
+            .with_context(|| format!("Failed to create {}", queue_directory.display()))?;
+
+        Ok(Self {
+            queue_directory,
+            global_webhooks: global_webhooks.into(),
+            wake: Arc::default(),
+        })
+    }
+
+    fn delivery_path(&self, id: &str) -> PathBuf {
+        self.queue_directory
+            .join(id)
+            .with_extension(DELIVERY_EXTENSION)
+    }
+
+    fn save_delivery(&self, delivery: &Delivery) -> Result<()> {
+        let path = self.delivery_path(&delivery.id);
+        let temporary_path = path.with_extension("tmp");
+
+        std::fs::write(
+            &temporary_path,
+            serde_json::to_string(delivery).context("Failed to serialize delivery")?,
+        )
+        .with_context(|| format!("Failed to write {}", temporary_path.display()))?;
+
+        std::fs::rename(&temporary_path, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn load_delivery(&self, path: &Path) -> Result<Delivery> {
+        serde_json::from_slice(
+            &std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+        )
+        .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn enqueue(&self, webhook: &Webhook, event: &Event) -> Result<()> {
+        use rand::Rng;
+        use time::format_description::well_known::Rfc3339;
+
+        let now = time::OffsetDateTime::now_utc();
+
+        let id = format!(
+            "{}_{:016X}",
+            now.unix_timestamp(),
+            rand::thread_rng().gen::<u64>()
+        );
+
+        let payload = serde_json::to_string(&Payload {
+            id: &id,
+            timestamp: now.format(&Rfc3339).context("Failed to format timestamp")?,
+            event,
+        })
+        .context("Failed to serialize event")?;
+
+        self.save_delivery(&Delivery {
+            id,
+            url: webhook.url.clone(),
+            signature: webhook.signature(&payload),
+            event: event.kind().into(),
+            payload,
+            attempts: 0,
+            next_attempt: unix_time(SystemTime::now()),
+        })
+    }
+
+    /// Queues the event for delivery to the given webhooks and the global webhooks
+    pub fn send(&self, webhooks: &[Webhook], event: &Event) {
+        let mut sent = false;
+
+        for webhook in self.global_webhooks.iter().chain(webhooks) {
+            match self.enqueue(webhook, event) {
+                Ok(()) => sent = true,
+                Err(err) => tracing::error!("Failed to queue webhook: {err:#}"),
+            }
+        }
+
+        if sent {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Attempts all deliveries which are due, returning the time of the next pending attempt
+    async fn deliver_due(&self, client: &reqwest::Client) -> Result<Option<u64>> {
+        let mut next_attempt = None::<u64>;
+
+        for entry in std::fs::read_dir(&self.queue_directory)
+            .with_context(|| format!("Failed to read {}", self.queue_directory.display()))?
+        {
+            let path = entry
+                .with_context(|| {
+                    format!("Failed to read entry in {}", self.queue_directory.display())
+                })?
+                .path();
+
+            if path.extension().and_then(|extension| extension.to_str()) != Some(DELIVERY_EXTENSION)
+            {
+                continue;
+            }
+
+            let mut delivery = match self.load_delivery(&path) {
+                Ok(delivery) => delivery,
+                Err(err) => {
+                    tracing::error!("{err:#}");
+                    continue;
+                }
+            };
+
+            if delivery.next_attempt > unix_time(SystemTime::now()) {
+                next_attempt = Some(next_attempt.unwrap_or(u64::MAX).min(delivery.next_attempt));
+                continue;
+            }
+
+            delivery.attempts += 1;
+
+            match delivery.attempt(client).await {
+                Ok(()) => {
+                    tracing::debug!(
+                        id = %delivery.id,
+                        "Delivered webhook to {}",
+                        delivery.url
+                    );
+                }
+                Err(err) if delivery.attempts < MAXIMUM_ATTEMPTS => {
+                    let retry_delay = delivery.retry_delay();
+
+                    tracing::warn!(
+                        id = %delivery.id,
+                        "{err:#}. Retrying in {}s",
+                        retry_delay.as_secs()
+                    );
+
+                    delivery.next_attempt = unix_time(SystemTime::now() + retry_delay);
+
+                    next_attempt =
+                        Some(next_attempt.unwrap_or(u64::MAX).min(delivery.next_attempt));
+
+                    self.save_delivery(&delivery)?;
+
+                    continue;
+                }
+                Err(err) => {
+                    tracing::error!(
+                        id = %delivery.id,
+                        "{err:#}. Giving up after {} attempts",
+                        delivery.attempts
+                    );
+                }
+            }
+
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+
+        Ok(next_attempt)
+    }
+
+    pub async fn run(self, shutdown_signal: impl Future<Output = ()>) {
+        let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(err) => {
+                tracing::error!("Failed to create webhook client: {err}");
+                return;
+            }
+        };
+
+        tokio::pin!(shutdown_signal);
+
+        loop {
+            let delay = match self.deliver_due(&client).await {
+                Ok(Some(next_attempt)) => {
+                    Duration::from_secs(next_attempt.saturating_sub(unix_time(SystemTime::now())))
+                }
+                Ok(None) => MAXIMUM_RETRY_DELAY,
+                Err(err) => {
+                    tracing::error!("Failed to deliver webhooks: {err:#}");
+                    INITIAL_RETRY_DELAY
+                }
+            };
+
+            tokio::select! {
+                () = &mut shutdown_signal => return,
+                () = self.wake.notified() => (),
+                () = tokio::time::sleep(delay) => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Bytes,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Extension, Router,
+    };
+
+    use super::*;
+    use crate::{
+        controller::{ByteCount, Token},
+        events::UploadedFile,
+    };
+
+    const SECRET: &str = "webhook secret";
+    const TOKEN: &str = "20240102_0304_0123456789ABCDEF0123456789ABCDEF";
+
+    /// Records the requests it receives, and responds with the given status
+    #[derive(Default)]
+    struct Receiver {
+        status: Option<StatusCode>,
+        requests: Vec<(HeaderMap, Bytes)>,
+    }
+
+    async fn receive(
+        headers: HeaderMap,
+        body: Bytes,
+        Extension(receiver): Extension<Arc<Mutex<Receiver>>>,
+    ) -> StatusCode {
+        let mut receiver = receiver.lock().unwrap();
+
+        receiver.requests.push((headers, body));
+        receiver.status.unwrap_or(StatusCode::OK)
+    }
+
+    /// Starts a webhook receiver and a queue in a new directory
+    fn start(secret: Option<&str>) -> (Webhooks, Arc<Mutex<Receiver>>) {
+        use rand::Rng;
+
+        let receiver = Arc::new(Mutex::new(Receiver::default()));
+
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .layer(Extension(receiver.clone()));
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+
+        let url = format!("http://{}/hook", server.local_addr());
+
+        tokio::spawn(server);
+
+        let queue_directory = std::env::temp_dir().join(format!(
+            "file-sharer-webhooks-{:016X}",
+            rand::thread_rng().gen::<u64>()
+        ));
+
+        let webhooks = Webhooks::new(
+            queue_directory,
+            vec![Webhook {
+                url,
+                secret: secret.map(String::from),
+            }],
+        )
+        .unwrap();
+
+        (webhooks, receiver)
+    }
+
+    fn event() -> Event {
+        Event::UploadCompleted {
+            token: TOKEN.parse().unwrap(),
+            name: String::from("Photos"),
+            files: vec![UploadedFile {
+                name: String::from("photo.jpg"),
+                size: ByteCount(1234),
+                sha256: String::new(),
+            }],
+        }
+    }
+
+    fn queued_files(webhooks: &Webhooks) -> Vec<String> {
+        std::fs::read_dir(&webhooks.queue_directory)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect()
+    }
+
+    fn expected_signature(payload: &[u8]) -> String {
+        use hmac::Mac;
+
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(payload);
+
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[tokio::test]
+    async fn delivery_is_signed() {
+        let (webhooks, receiver) = start(Some(SECRET));
+
+        webhooks.send(&[], &event());
+
+        for queued in queued_files(&webhooks) {
+            assert!(!queued.contains(SECRET));
+            assert!(!queued.contains(TOKEN));
+        }
+
+        let next_attempt = webhooks.deliver_due(&reqwest::Client::new()).await.unwrap();
+
+        assert_eq!(next_attempt, None);
+        assert!(queued_files(&webhooks).is_empty());
+
+        let receiver = receiver.lock().unwrap();
+        let (headers, body) = &receiver.requests[0];
+
+        assert_eq!(receiver.requests.len(), 1);
+        assert_eq!(headers[EVENT_HEADER], "upload_completed");
+        assert_eq!(headers[SIGNATURE_HEADER], expected_signature(body).as_str());
+
+        let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+
+        assert_eq!(payload["event"], "upload_completed");
+        assert_eq!(payload["id"], headers[DELIVERY_HEADER].to_str().unwrap());
+        assert_eq!(
+            payload["token_id"],
+            TOKEN.parse::<Token>().unwrap().log_id()
+        );
+
+        std::fs::remove_dir_all(&webhooks.queue_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn delivery_is_unsigned_without_secret() {
+        let (webhooks, receiver) = start(None);
+
+        webhooks.send(&[], &event());
+        webhooks.deliver_due(&reqwest::Client::new()).await.unwrap();
+
+        let receiver = receiver.lock().unwrap();
+
+        assert_eq!(receiver.requests.len(), 1);
+        assert!(!receiver.requests[0].0.contains_key(SIGNATURE_HEADER));
+
+        std::fs::remove_dir_all(&webhooks.queue_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried() {
+        let (webhooks, receiver) = start(Some(SECRET));
+        let client = reqwest::Client::new();
+
+        receiver.lock().unwrap().status = Some(StatusCode::INTERNAL_SERVER_ERROR);
+
+        webhooks.send(&[], &event());
+
+        let before = unix_time(SystemTime::now());
+        let next_attempt = webhooks.deliver_due(&client).await.unwrap().unwrap();
+
+        assert!(next_attempt >= before + INITIAL_RETRY_DELAY.as_secs());
+
+        let path = std::fs::read_dir(&webhooks.queue_directory)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+
+        let mut delivery = webhooks.load_delivery(&path).unwrap();
+
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.next_attempt, next_attempt);
+
+        // Isn't due yet
+        assert_eq!(
+            webhooks.deliver_due(&client).await.unwrap(),
+            Some(next_attempt)
+        );
+        assert_eq!(receiver.lock().unwrap().requests.len(), 1);
+
+        delivery.next_attempt = before;
+        webhooks.save_delivery(&delivery).unwrap();
+
+        receiver.lock().unwrap().status = None;
+
+        assert_eq!(webhooks.deliver_due(&client).await.unwrap(), None);
+        assert!(queued_files(&webhooks).is_empty());
+
+        let receiver = receiver.lock().unwrap();
+
+        assert_eq!(receiver.requests.len(), 2);
+        assert_eq!(receiver.requests[0].1, receiver.requests[1].1);
+        assert_eq!(
+            receiver.requests[0].0[DELIVERY_HEADER],
+            receiver.requests[1].0[DELIVERY_HEADER]
+        );
+        assert_eq!(
+            receiver.requests[1].0[SIGNATURE_HEADER],
+            expected_signature(&receiver.requests[1].1).as_str()
+        );
+
+        std::fs::remove_dir_all(&webhooks.queue_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn delivery_is_abandoned_after_maximum_attempts() {
+        let (webhooks, receiver) = start(Some(SECRET));
+        let client = reqwest::Client::new();
+
+        receiver.lock().unwrap().status = Some(StatusCode::INTERNAL_SERVER_ERROR);
+
+        webhooks.send(&[], &event());
+
+        let path = std::fs::read_dir(&webhooks.queue_directory)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+
+        let mut delivery = webhooks.load_delivery(&path).unwrap();
+        delivery.attempts = MAXIMUM_ATTEMPTS - 1;
+        webhooks.save_delivery(&delivery).unwrap();
+
+        assert_eq!(webhooks.deliver_due(&client).await.unwrap(), None);
+        assert!(queued_files(&webhooks).is_empty());
+        assert_eq!(receiver.lock().unwrap().requests.len(), 1);
+
+        std::fs::remove_dir_all(&webhooks.queue_directory).unwrap();
+    }
+}
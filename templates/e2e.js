@@ -0,0 +1,68 @@
+// End-to-end encryption of shared files. The key is only ever held by the browser, and is
+// passed between browsers in the URL fragment, which is never sent to the server.
+// Files are stored as a random 12 byte IV followed by the AES-GCM ciphertext, and filenames
+// as the same, base64url encoded
+const E2E_IV_LENGTH = 12;
+const E2E_TAG_LENGTH = 16;
+
+function e2eEncodeBase64Url(bytes) {
+    let binary = "";
+
+    for (const byte of new Uint8Array(bytes)) {
+        binary += String.fromCharCode(byte);
+    }
+
+    return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function e2eDecodeBase64Url(text) {
+    const binary = atob(text.replace(/-/g, "+").replace(/_/g, "/"));
+
+    return Uint8Array.from(binary, character => character.charCodeAt(0));
+}
+
+function e2eKeyFromFragment() {
+    return decodeURIComponent(window.location.hash.slice(1));
+}
+
+function e2eGenerateKey() {
+    return e2eEncodeBase64Url(crypto.getRandomValues(new Uint8Array(32)));
+}
+
+function e2eImportKey(key) {
+    return crypto.subtle.importKey("raw", e2eDecodeBase64Url(key), "AES-GCM", false, ["encrypt", "decrypt"]);
+}
+
+async function e2eEncrypt(cryptoKey, plaintext) {
+    const iv = crypto.getRandomValues(new Uint8Array(E2E_IV_LENGTH));
+    const ciphertext = await crypto.subtle.encrypt({ name: "AES-GCM", iv }, cryptoKey, plaintext);
+
+    const encrypted = new Uint8Array(E2E_IV_LENGTH + ciphertext.byteLength);
+
+    encrypted.set(iv);
+    encrypted.set(new Uint8Array(ciphertext), E2E_IV_LENGTH);
+
+    return encrypted;
+}
+
+function e2eDecrypt(cryptoKey, encrypted) {
+    const bytes = new Uint8Array(encrypted);
+
+    return crypto.subtle.decrypt(
+        { name: "AES-GCM", iv: bytes.slice(0, E2E_IV_LENGTH) },
+        cryptoKey,
+        bytes.slice(E2E_IV_LENGTH)
+    );
+}
+
+async function e2eEncryptFilename(cryptoKey, filename) {
+    return e2eEncodeBase64Url(await e2eEncrypt(cryptoKey, new TextEncoder().encode(filename)));
+}
+
+async function e2eDecryptFilename(cryptoKey, encryptedFilename) {
+    return new TextDecoder().decode(await e2eDecrypt(cryptoKey, e2eDecodeBase64Url(encryptedFilename)));
+}
+
+function e2ePlaintextSize(size) {
+    return Math.max(size - E2E_IV_LENGTH - E2E_TAG_LENGTH, 0);
+}